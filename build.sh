@@ -8,12 +8,25 @@ rm -rf iso
 RUSTFLAGS="-C link-arg=-Tlinker.ld" \
     cargo build --target target.json -Z build-std=core,alloc,compiler_builtins -Z build-std-features=compiler-builtins-mem --release
 
+#user programs, linked at USER_BASE
+as user/init.S -o target/init.o
+ld -static -nostdlib -z max-page-size=0x1000 -Ttext-segment=0x8000000000 -e _start \
+    target/init.o -o target/init
+
 #post
 mkdir -p iso/boot/grub
 cp target/target/release/maizeOS iso/boot/kernel
+cp target/init iso/boot/init
 cp grub.cfg iso/boot/grub/
 grub2-mkrescue -o maizeOS.iso iso
 
 #run
 # qemu-system-x86_64 -machine q35 -cpu qemu64 -m 256M -cdrom maizeOS.iso -serial stdio  -no-reboot
+# the boot log should end with:
+#   INFO  kernel: module "init": <size> bytes
+#   INFO  process: process 1 (init) started
+#   INFO  kernel: started "init" as pid 1
+#   ...
+#   init: hello from user space
+#   INFO  process: process 1 (init) exited
 
@@ -0,0 +1,379 @@
+// VT100/ANSI escape sequence parser shared by the text consoles.
+//
+// The parser owns the escape state, the current SGR attributes and the saved
+// cursor; backends only implement `AnsiTarget` (put a char, move the cursor,
+// erase cells, change colors).
+
+use crate::vga_buffer::Color;
+
+const MAX_PARAMS: usize = 8;
+
+pub const DEFAULT_FG: Color = Color::Yellow;
+pub const DEFAULT_BG: Color = Color::Black;
+/// What `ESC[0m` goes back to on every console.
+pub const DEFAULT_ATTR: Attr = Attr::new(DEFAULT_FG, DEFAULT_BG);
+
+pub trait AnsiTarget {
+    /// (rows, cols)
+    fn size(&self) -> (usize, usize);
+    /// (row, col)
+    fn cursor(&self) -> (usize, usize);
+    fn set_cursor(&mut self, row: usize, col: usize);
+    /// Anything that isn't part of an escape sequence, including `\n` etc.
+    fn put(&mut self, c: char);
+    /// Blanks `row[from..to]` with the current background.
+    fn erase(&mut self, row: usize, from: usize, to: usize);
+    fn set_colors(&mut self, fg: TermColor, bg: TermColor);
+}
+
+/// A color as SGR gives it: one of the 16 console colors, or RGB from
+/// `38;2;r;g;b` and the 256-color cube. Backends that cannot show RGB take
+/// the nearest console color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TermColor {
+    Palette(Color),
+    Rgb(u8, u8, u8),
+}
+
+impl TermColor {
+    pub fn rgb(self) -> (u8, u8, u8) {
+        match self {
+            TermColor::Palette(c) => c.rgb(),
+            TermColor::Rgb(r, g, b) => (r, g, b),
+        }
+    }
+
+    pub fn nearest(self) -> Color {
+        match self {
+            TermColor::Palette(c) => c,
+            TermColor::Rgb(r, g, b) => Color::nearest(r, g, b),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attr {
+    pub fg: TermColor,
+    pub bg: TermColor,
+    pub bold: bool,
+    pub reverse: bool,
+}
+
+impl Attr {
+    pub const fn new(fg: Color, bg: Color) -> Self {
+        Self {
+            fg: TermColor::Palette(fg),
+            bg: TermColor::Palette(bg),
+            bold: false,
+            reverse: false,
+        }
+    }
+
+    /// Effective (fg, bg) after bold/reverse. Bold brightens console colors
+    /// only.
+    pub fn colors(&self) -> (TermColor, TermColor) {
+        let fg = match self.fg {
+            TermColor::Palette(c) if self.bold => TermColor::Palette(c.bright()),
+            fg => fg,
+        };
+        if self.reverse {
+            (self.bg, fg)
+        } else {
+            (fg, self.bg)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+pub struct Parser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    nparams: usize,
+    private: bool,
+    default: Attr,
+    attr: Attr,
+    saved: (usize, usize, Attr),
+}
+
+impl Parser {
+    pub const fn new(default: Attr) -> Self {
+        Self {
+            state: State::Ground,
+            params: [0; MAX_PARAMS],
+            nparams: 0,
+            private: false,
+            default,
+            attr: default,
+            saved: (0, 0, default),
+        }
+    }
+
+    pub fn attr(&self) -> Attr {
+        self.attr
+    }
+
+    pub fn set_attr<T: AnsiTarget>(&mut self, attr: Attr, t: &mut T) {
+        self.attr = attr;
+        let (fg, bg) = attr.colors();
+        t.set_colors(fg, bg);
+    }
+
+    pub fn feed<T: AnsiTarget>(&mut self, c: char, t: &mut T) {
+        match self.state {
+            State::Ground => {
+                if c == '\x1b' {
+                    self.state = State::Escape;
+                } else {
+                    t.put(c);
+                }
+            }
+            State::Escape => {
+                self.state = State::Ground;
+                match c {
+                    '[' => {
+                        self.params = [0; MAX_PARAMS];
+                        self.nparams = 0;
+                        self.private = false;
+                        self.state = State::Csi;
+                    }
+                    '7' => self.save_cursor(t),
+                    '8' => self.restore_cursor(t),
+                    'c' => {
+                        self.set_attr(self.default, t);
+                        self.erase_display(2, t);
+                        t.set_cursor(0, 0);
+                    }
+                    _ => {}
+                }
+            }
+            State::Csi => match c {
+                '0'..='9' => {
+                    if self.nparams == 0 {
+                        self.nparams = 1;
+                    }
+                    if let Some(p) = self.params.get_mut(self.nparams - 1) {
+                        *p = p.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                    }
+                }
+                ';' => {
+                    if self.nparams == 0 {
+                        self.nparams = 1;
+                    }
+                    // one past the end marks the rest as dropped, so their
+                    // digits do not land in the last kept parameter
+                    self.nparams = (self.nparams + 1).min(MAX_PARAMS + 1);
+                }
+                '?' => self.private = true,
+                '\x40'..='\x7e' => {
+                    self.state = State::Ground;
+                    if !self.private {
+                        self.dispatch_csi(c, t);
+                    }
+                }
+                // anything else aborts the sequence
+                _ => self.state = State::Ground,
+            },
+        }
+    }
+
+    fn param(&self, i: usize, default: u16) -> u16 {
+        match self.params.get(i) {
+            Some(&p) if i < self.nparams && p != 0 => p,
+            _ => default,
+        }
+    }
+
+    fn dispatch_csi<T: AnsiTarget>(&mut self, c: char, t: &mut T) {
+        let (rows, cols) = t.size();
+        let (row, col) = t.cursor();
+        let n = self.param(0, 1) as usize;
+
+        match c {
+            'A' => t.set_cursor(row.saturating_sub(n), col),
+            'B' => t.set_cursor((row + n).min(rows - 1), col),
+            'C' => t.set_cursor(row, (col + n).min(cols - 1)),
+            'D' => t.set_cursor(row, col.saturating_sub(n)),
+            'E' => t.set_cursor((row + n).min(rows - 1), 0),
+            'F' => t.set_cursor(row.saturating_sub(n), 0),
+            'G' => t.set_cursor(row, (n - 1).min(cols - 1)),
+            'H' | 'f' => {
+                let r = self.param(0, 1) as usize - 1;
+                let c = self.param(1, 1) as usize - 1;
+                t.set_cursor(r.min(rows - 1), c.min(cols - 1));
+            }
+            'J' => self.erase_display(self.param(0, 0), t),
+            'K' => match self.param(0, 0) {
+                0 => t.erase(row, col, cols),
+                1 => t.erase(row, 0, col + 1),
+                _ => t.erase(row, 0, cols),
+            },
+            'm' => self.sgr(t),
+            's' => self.save_cursor(t),
+            'u' => self.restore_cursor(t),
+            _ => {}
+        }
+    }
+
+    fn erase_display<T: AnsiTarget>(&mut self, mode: u16, t: &mut T) {
+        let (rows, cols) = t.size();
+        let (row, col) = t.cursor();
+        match mode {
+            0 => {
+                t.erase(row, col, cols);
+                for r in row + 1..rows {
+                    t.erase(r, 0, cols);
+                }
+            }
+            1 => {
+                for r in 0..row {
+                    t.erase(r, 0, cols);
+                }
+                t.erase(row, 0, col + 1);
+            }
+            _ => {
+                for r in 0..rows {
+                    t.erase(r, 0, cols);
+                }
+            }
+        }
+    }
+
+    fn save_cursor<T: AnsiTarget>(&mut self, t: &mut T) {
+        let (row, col) = t.cursor();
+        self.saved = (row, col, self.attr);
+    }
+
+    fn restore_cursor<T: AnsiTarget>(&mut self, t: &mut T) {
+        let (row, col, attr) = self.saved;
+        let (rows, cols) = t.size();
+        t.set_cursor(row.min(rows - 1), col.min(cols - 1));
+        self.set_attr(attr, t);
+    }
+
+    fn sgr<T: AnsiTarget>(&mut self, t: &mut T) {
+        let mut attr = self.attr;
+        let count = self.nparams.clamp(1, MAX_PARAMS);
+        let mut i = 0;
+
+        while i < count {
+            let p = self.params.get(i).copied().unwrap_or(0);
+            match p {
+                0 => attr = self.default,
+                1 => attr.bold = true,
+                22 => attr.bold = false,
+                7 => attr.reverse = true,
+                27 => attr.reverse = false,
+                30..=37 => attr.fg = palette((p - 30) as u8),
+                39 => attr.fg = self.default.fg,
+                40..=47 => attr.bg = palette((p - 40) as u8),
+                49 => attr.bg = self.default.bg,
+                90..=97 => attr.fg = palette((p - 90) as u8 + 8),
+                100..=107 => attr.bg = palette((p - 100) as u8 + 8),
+                38 | 48 => {
+                    let (color, used) = self.extended_color(i + 1);
+                    if let Some(color) = color {
+                        if p == 38 {
+                            attr.fg = color;
+                        } else {
+                            attr.bg = color;
+                        }
+                    }
+                    i += used;
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+
+        self.set_attr(attr, t);
+    }
+
+    // `38;5;n` (256 colors) or `38;2;r;g;b`.
+    fn extended_color(&self, i: usize) -> (Option<TermColor>, usize) {
+        let arg = |k: usize| self.params.get(i + k).copied().unwrap_or(0);
+        match arg(0) {
+            5 => {
+                let n = arg(1);
+                let color = if n < 16 {
+                    palette(n as u8)
+                } else if n < 232 {
+                    let n = n - 16;
+                    let level = |v: u16| if v == 0 { 0 } else { (55 + v * 40) as u8 };
+                    TermColor::Rgb(level(n / 36), level((n / 6) % 6), level(n % 6))
+                } else {
+                    let v = (8 + (n.min(255) - 232) * 10) as u8;
+                    TermColor::Rgb(v, v, v)
+                };
+                (Some(color), 2)
+            }
+            2 => (
+                Some(TermColor::Rgb(
+                    arg(1).min(255) as u8,
+                    arg(2).min(255) as u8,
+                    arg(3).min(255) as u8,
+                )),
+                4,
+            ),
+            _ => (None, 0),
+        }
+    }
+}
+
+fn palette(n: u8) -> TermColor {
+    TermColor::Palette(Color::from_ansi(n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Screen {
+        colors: (TermColor, TermColor),
+    }
+
+    impl AnsiTarget for Screen {
+        fn size(&self) -> (usize, usize) {
+            (25, 80)
+        }
+        fn cursor(&self) -> (usize, usize) {
+            (0, 0)
+        }
+        fn set_cursor(&mut self, _row: usize, _col: usize) {}
+        fn put(&mut self, _c: char) {}
+        fn erase(&mut self, _row: usize, _from: usize, _to: usize) {}
+        fn set_colors(&mut self, fg: TermColor, bg: TermColor) {
+            self.colors = (fg, bg);
+        }
+    }
+
+    fn feed(parser: &mut Parser, screen: &mut Screen, s: &str) {
+        for c in s.chars() {
+            parser.feed(c, screen);
+        }
+    }
+
+    #[test]
+    fn sgr_drops_parameters_past_the_limit() {
+        let mut parser = Parser::new(DEFAULT_ATTR);
+        let mut screen = Screen {
+            colors: DEFAULT_ATTR.colors(),
+        };
+
+        // the 9th and later parameters are ignored, not read out of bounds
+        feed(&mut parser, &mut screen, "\x1b[0;0;0;0;0;0;0;31;1;32;7m");
+        assert_eq!(parser.attr().fg, palette(1));
+        assert!(!parser.attr().bold);
+        assert!(!parser.attr().reverse);
+        assert_eq!(parser.attr().bg, DEFAULT_ATTR.bg);
+
+        // the parser is back in the ground state afterwards
+        feed(&mut parser, &mut screen, "\x1b[;;;;;;;;;;;;1m");
+        assert_eq!(parser.attr(), DEFAULT_ATTR);
+    }
+}
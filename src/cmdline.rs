@@ -0,0 +1,29 @@
+// Kernel command line: whitespace separated `key=value` pairs and bare flags.
+
+use crate::mb2;
+use crate::sync::once::OnceCell;
+
+static CMDLINE: OnceCell<&'static str> = OnceCell::new();
+
+pub fn init(mb2_info_phys: usize) {
+    let s = mb2::get_cmdline(mb2_info_phys).unwrap_or("");
+    let _ = CMDLINE.set(s.trim());
+}
+
+/// Empty until `init` has run.
+pub fn get() -> &'static str {
+    CMDLINE.get().copied().unwrap_or("")
+}
+
+/// Every value given for `key`, in command line order.
+pub fn values(key: &str) -> impl Iterator<Item = &'static str> + '_ {
+    get().split_ascii_whitespace().filter_map(move |arg| {
+        let (k, v) = arg.split_once('=')?;
+        (k == key).then_some(v)
+    })
+}
+
+/// The last value given for `key`.
+pub fn value(key: &str) -> Option<&'static str> {
+    values(key).last()
+}
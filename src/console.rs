@@ -0,0 +1,227 @@
+// Console registry. Backends register themselves once they are usable and
+// `print!`/`println!` format once and write to every enabled console.
+//
+// `console=<name>[,<options>]` on the kernel command line (may repeat, one
+// console each) restricts output to the listed consoles; without it all are
+// enabled.
+//
+// A `print!` holds the output lock for the whole call, with interrupts off,
+// so lines never interleave and an IRQ handler that prints cannot spin on a
+// lock held by the code it interrupted. The registry itself is read-mostly
+// and sits behind a separate reader-writer lock. Output that cannot take the
+// lock safely (re-entry on the same CPU, or a panic while another holder may
+// be dead) goes to the emergency serial path instead.
+
+pub mod mem;
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::sync::irq_spinlock::{IrqSpinLock, IrqSpinLockGuard};
+use crate::sync::rwlock::{RwSpinLock, RwSpinLockReadGuard};
+use crate::{cmdline, cpu, framebuffer, irq, log, serial, vga_buffer, warn};
+
+const MAX_CONSOLES: usize = 8;
+const FORMAT_BUFFER: usize = 256;
+const PANIC_SPINS: usize = 1_000_000;
+
+pub trait Console: Sync {
+    /// Name used by `console=` (e.g. `ttyS0`, `vga`, `fb`, `mem`).
+    fn name(&self) -> &'static str;
+    fn write_str(&self, s: &str);
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    console: &'static dyn Console,
+    enabled: bool,
+}
+
+type Registry = [Option<Entry>; MAX_CONSOLES];
+
+static CONSOLES: RwSpinLock<Registry> = RwSpinLock::new([None; MAX_CONSOLES]);
+static OUTPUT: IrqSpinLock<()> = IrqSpinLock::new(());
+
+/// CPU currently holding `OUTPUT`.
+static OWNER: AtomicUsize = AtomicUsize::new(cpu::NO_CPU);
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+fn enabled_by_cmdline(name: &str) -> bool {
+    let mut any = false;
+    for arg in cmdline::values("console") {
+        any = true;
+        // `ttyS0,115200` style options after the name are the backend's business
+        if arg.split(',').next() == Some(name) {
+            return true;
+        }
+    }
+    !any
+}
+
+pub fn register(console: &'static dyn Console) {
+    let enabled = enabled_by_cmdline(console.name());
+    // writers keep interrupts off: a print from an IRQ handler would
+    // otherwise wait on the write lock it interrupted
+    let added = irq::without_interrupts(|| {
+        let mut consoles = CONSOLES.write();
+        let slot = consoles.iter_mut().find(|e| e.is_none());
+        slot.map(|slot| *slot = Some(Entry { console, enabled }))
+    });
+    if added.is_none() {
+        warn!("no slot for {}", console.name());
+        return;
+    }
+
+    // catch the new console up on everything logged before it existed
+    if enabled {
+        log::replay(console);
+    }
+}
+
+/// Turns a registered console on or off. Returns false if `name` is unknown.
+#[allow(dead_code)]
+pub fn set_enabled(name: &str, enabled: bool) -> bool {
+    irq::without_interrupts(|| {
+        let mut consoles = CONSOLES.write();
+        match consoles
+            .iter_mut()
+            .flatten()
+            .find(|e| e.console.name() == name)
+        {
+            Some(e) => {
+                e.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// From here on output only try-locks: the CPU holding a console lock may
+/// be the one that crashed. Called first thing on panic and fatal exceptions.
+pub fn begin_panic() {
+    PANICKING.store(true, Ordering::Relaxed);
+}
+
+/// The output lock, held by this CPU, and the registry. Fields drop in
+/// order, so the registry is released before interrupts come back on.
+struct Output {
+    consoles: RwSpinLockReadGuard<'static, Registry>,
+    _output: IrqSpinLockGuard<'static, ()>,
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        OWNER.store(cpu::NO_CPU, Ordering::Relaxed);
+    }
+}
+
+fn lock_output() -> Option<Output> {
+    let cpu = cpu::id();
+    if OWNER.load(Ordering::Relaxed) == cpu {
+        // a fault or NMI in the middle of our own print
+        return None;
+    }
+
+    let (output, consoles) = if PANICKING.load(Ordering::Relaxed) {
+        let output = spin_try(|| OUTPUT.try_lock())?;
+        (output, spin_try(|| CONSOLES.try_read())?)
+    } else {
+        let output = OUTPUT.lock();
+        (output, CONSOLES.read())
+    };
+    OWNER.store(cpu, Ordering::Relaxed);
+    Some(Output {
+        consoles,
+        _output: output,
+    })
+}
+
+/// Retries `f` for a bounded time.
+fn spin_try<G>(mut f: impl FnMut() -> Option<G>) -> Option<G> {
+    for _ in 0..PANIC_SPINS {
+        if let Some(guard) = f() {
+            return Some(guard);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+/// Collects formatted pieces so each console sees a few large writes
+/// instead of one per format argument.
+struct FanOut<'a> {
+    consoles: &'a Registry,
+    buf: [u8; FORMAT_BUFFER],
+    len: usize,
+}
+
+impl FanOut<'_> {
+    fn write_all(&self, s: &str) {
+        for e in self.consoles.iter().flatten().filter(|e| e.enabled) {
+            e.console.write_str(s);
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.len > 0 {
+            // only whole `&str`s are ever appended
+            self.write_all(core::str::from_utf8(&self.buf[..self.len]).unwrap_or(""));
+            self.len = 0;
+        }
+    }
+}
+
+impl fmt::Write for FanOut<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.len + s.len() > FORMAT_BUFFER {
+            self.flush();
+        }
+        if s.len() > FORMAT_BUFFER {
+            self.write_all(s);
+        } else {
+            self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+            self.len += s.len();
+        }
+        Ok(())
+    }
+}
+
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let Some(output) = lock_output() else {
+        serial::_print(args);
+        return;
+    };
+    let mut out = FanOut {
+        consoles: &output.consoles,
+        buf: [0; FORMAT_BUFFER],
+        len: 0,
+    };
+    let _ = out.write_fmt(args);
+    out.flush();
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(core::format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($fmt:expr) => ($crate::print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::print!(concat!($fmt, "\n"), $($arg)*));
+}
+
+/// Scrollback navigation; only the VGA text console keeps history.
+pub fn scroll_view(lines: isize) {
+    if framebuffer::WRITER.lock().is_none() {
+        vga_buffer::WRITER.get().lock().scroll_view(lines);
+    }
+}
+
+/// Lines moved by one Shift+PgUp/PgDn.
+pub fn page_lines() -> usize {
+    vga_buffer::BUFFER_HEIGHT / 2
+}
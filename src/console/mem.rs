@@ -0,0 +1,60 @@
+// In-memory console: keeps the most recent console output in a ring buffer,
+// so it can be inspected after the fact (e.g. from a debugger).
+
+use super::{Console, register};
+use crate::sync::irq_spinlock::IrqSpinLock;
+
+const MEM_CONSOLE_SIZE: usize = 16 * 1024;
+
+struct Ring {
+    buf: [u8; MEM_CONSOLE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+pub struct MemConsole {
+    ring: IrqSpinLock<Ring>,
+}
+
+pub static MEM_CONSOLE: MemConsole = MemConsole {
+    ring: IrqSpinLock::new(Ring {
+        buf: [0; MEM_CONSOLE_SIZE],
+        head: 0,
+        len: 0,
+    }),
+};
+
+impl MemConsole {
+    /// Copies the retained output, oldest first, into `out`; returns the
+    /// number of bytes copied.
+    #[allow(dead_code)]
+    pub fn read(&self, out: &mut [u8]) -> usize {
+        let ring = self.ring.lock();
+        let n = ring.len.min(out.len());
+        let start = (ring.head + MEM_CONSOLE_SIZE - ring.len) % MEM_CONSOLE_SIZE;
+        for (i, b) in out[..n].iter_mut().enumerate() {
+            *b = ring.buf[(start + i) % MEM_CONSOLE_SIZE];
+        }
+        n
+    }
+}
+
+pub fn init() {
+    register(&MEM_CONSOLE);
+}
+
+impl Console for MemConsole {
+    fn name(&self) -> &'static str {
+        "mem"
+    }
+
+    fn write_str(&self, s: &str) {
+        let mut ring = self.ring.lock();
+        for &b in s.as_bytes() {
+            let head = ring.head;
+            ring.buf[head] = b;
+            ring.head = (head + 1) % MEM_CONSOLE_SIZE;
+            ring.len = (ring.len + 1).min(MEM_CONSOLE_SIZE);
+        }
+    }
+}
@@ -0,0 +1,44 @@
+// Per-CPU identity. Only the bootstrap processor runs so far, so every CPU
+// is CPU 0; callers use `id` wherever the answer will matter once APs boot.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub const MAX_CPUS: usize = 8;
+
+/// Never a valid CPU id, for "no owner" in lock bookkeeping.
+pub const NO_CPU: usize = usize::MAX;
+
+pub fn id() -> usize {
+    0
+}
+
+static PREEMPT_COUNT: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// Keeps the scheduler from switching threads on this CPU until the matching
+/// `preempt_enable`. Spinlocks do this while held, so a preempted holder can
+/// never leave other threads spinning on it. Nests.
+pub fn preempt_disable() {
+    PREEMPT_COUNT[id()].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn preempt_enable() {
+    PREEMPT_COUNT[id()].fetch_sub(1, Ordering::Relaxed);
+}
+
+pub fn preemptible() -> bool {
+    PREEMPT_COUNT[id()].load(Ordering::Relaxed) == 0
+}
+
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (lo, hi): (u32, u32);
+    unsafe {
+        core::arch::asm!("rdmsr", in("ecx") msr, out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags));
+    }
+    ((hi as u64) << 32) | lo as u64
+}
+
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    unsafe {
+        core::arch::asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack, preserves_flags));
+    }
+}
@@ -0,0 +1,345 @@
+// ELF64 loader for user programs.
+//
+// Takes a statically linked x86_64 executable (ET_EXEC) from memory, maps its
+// PT_LOAD segments into a fresh address space and builds the initial stack
+// the System V ABI describes:
+//
+//   [argc][argv pointers][NULL][envp pointers][NULL][auxv pairs][AT_NULL]
+//   ... then the strings and AT_RANDOM bytes near the top
+//
+// with rsp 16-byte aligned at argc. Segments are always readable and
+// executable: NX is not enabled yet, so only PF_W is honoured.
+
+use alloc::vec::Vec;
+
+use crate::frame_alloc::PAGE_SIZE;
+use crate::paging::{AddressSpace, MapError, USER, USER_BASE, USER_END, WRITABLE};
+use crate::process::{PROT_EXEC, PROT_READ, PROT_WRITE, Vma, VmaKind};
+use crate::time;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const PF_W: u32 = 2;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+const STACK_PAGES: usize = 16;
+const STACK_TOP: u64 = USER_END;
+const STACK_SIZE: u64 = STACK_PAGES as u64 * PAGE_SIZE;
+/// argv plus envp entries.
+const MAX_ARGS: usize = 64;
+/// Room for argument and environment strings, leaving the rest of the stack
+/// to the program.
+const MAX_ARG_BYTES: usize = (STACK_SIZE / 4) as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    NotElf,
+    /// Valid ELF, but not a 64-bit little-endian x86_64 executable.
+    Unsupported,
+    BadHeader,
+    BadSegment,
+    TooManyArgs,
+    Map(MapError),
+}
+
+impl From<MapError> for ElfError {
+    fn from(e: MapError) -> Self {
+        ElfError::Map(e)
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Header {
+    ident: [u8; 16],
+    e_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ProgramHeader {
+    p_type: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// A loaded program, ready to start.
+pub struct Program {
+    pub space: AddressSpace,
+    pub entry: u64,
+    /// Initial rsp, pointing at argc.
+    pub stack: u64,
+    /// What got mapped, in address order.
+    pub vmas: Vec<Vma>,
+}
+
+fn read<T: Copy>(image: &[u8], offset: u64) -> Result<T, ElfError> {
+    let end = offset
+        .checked_add(size_of::<T>() as u64)
+        .ok_or(ElfError::BadHeader)?;
+    if end > image.len() as u64 {
+        return Err(ElfError::BadHeader);
+    }
+    Ok(unsafe { (image.as_ptr().add(offset as usize) as *const T).read_unaligned() })
+}
+
+fn in_user_space(start: u64, len: u64) -> bool {
+    start >= USER_BASE && start.checked_add(len).is_some_and(|end| end <= USER_END)
+}
+
+fn check_header(image: &[u8]) -> Result<Header, ElfError> {
+    let hdr: Header = read(image, 0).map_err(|_| ElfError::NotElf)?;
+    if hdr.ident[..4] != ELF_MAGIC {
+        return Err(ElfError::NotElf);
+    }
+    if hdr.ident[4] != ELFCLASS64
+        || hdr.ident[5] != ELFDATA2LSB
+        || hdr.ident[6] != EV_CURRENT
+        || hdr.e_type != ET_EXEC
+        || hdr.machine != EM_X86_64
+    {
+        return Err(ElfError::Unsupported);
+    }
+    if hdr.phentsize as usize != size_of::<ProgramHeader>() || hdr.phnum == 0 {
+        return Err(ElfError::BadHeader);
+    }
+    if !in_user_space(hdr.entry, 1) {
+        return Err(ElfError::BadHeader);
+    }
+    Ok(hdr)
+}
+
+/// Maps an ELF image into a new address space and sets up its stack.
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, ElfError> {
+    let hdr = check_header(image)?;
+    let space = AddressSpace::new()?;
+    let mut vmas = Vec::new();
+    match populate(&space, &mut vmas, image, &hdr, argv, envp) {
+        Ok(stack) => Ok(Program {
+            space,
+            entry: hdr.entry,
+            stack,
+            vmas,
+        }),
+        Err(e) => {
+            space.destroy();
+            Err(e)
+        }
+    }
+}
+
+fn populate(
+    space: &AddressSpace,
+    vmas: &mut Vec<Vma>,
+    image: &[u8],
+    hdr: &Header,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<u64, ElfError> {
+    let phdrs_len = hdr.phnum as u64 * hdr.phentsize as u64;
+    let mut phdr_addr = None;
+    let mut loaded_any = false;
+    for i in 0..hdr.phnum as u64 {
+        let ph: ProgramHeader = read(image, hdr.phoff + i * hdr.phentsize as u64)?;
+        match ph.p_type {
+            PT_LOAD => {
+                if let Some(vma) = load_segment(space, image, &ph)? {
+                    add_vma(vmas, vma)?;
+                }
+                loaded_any = true;
+                // the headers are usually inside the first segment
+                if ph.offset <= hdr.phoff && hdr.phoff + phdrs_len <= ph.offset + ph.filesz {
+                    phdr_addr.get_or_insert(ph.vaddr + (hdr.phoff - ph.offset));
+                }
+            }
+            PT_PHDR => phdr_addr = Some(ph.vaddr),
+            _ => {}
+        }
+    }
+    if !loaded_any {
+        return Err(ElfError::BadSegment);
+    }
+
+    let auxv = [
+        (AT_PHDR, phdr_addr.unwrap_or(0)),
+        (AT_PHENT, hdr.phentsize as u64),
+        (AT_PHNUM, hdr.phnum as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, hdr.entry),
+    ];
+    let stack = setup_stack(space, argv, envp, &auxv)?;
+    vmas.push(Vma {
+        start: STACK_TOP - STACK_SIZE,
+        end: STACK_TOP,
+        prot: PROT_READ | PROT_WRITE,
+        shared: false,
+        kind: VmaKind::Stack,
+    });
+    Ok(stack)
+}
+
+/// Appends `vma`; segments must come sorted by address. Where it shares
+/// pages with the previous segment, only those pages get both protections:
+/// the rest of each keeps its own, so text next to data stays read-only.
+fn add_vma(vmas: &mut Vec<Vma>, vma: Vma) -> Result<(), ElfError> {
+    let Some(last) = vmas.last_mut().filter(|last| last.end > vma.start) else {
+        vmas.push(vma);
+        return Ok(());
+    };
+    let prev = *last;
+    if vma.start < prev.start {
+        return Err(ElfError::BadSegment);
+    }
+    let shared_end = prev.end.min(vma.end);
+    last.end = vma.start;
+    if last.start == last.end {
+        vmas.pop();
+    }
+    vmas.push(Vma {
+        end: shared_end,
+        prot: prev.prot | vma.prot,
+        ..vma
+    });
+    if prev.end > shared_end {
+        vmas.push(Vma {
+            start: shared_end,
+            ..prev
+        });
+    } else if vma.end > shared_end {
+        vmas.push(Vma {
+            start: shared_end,
+            ..vma
+        });
+    }
+    Ok(())
+}
+
+fn load_segment(
+    space: &AddressSpace,
+    image: &[u8],
+    ph: &ProgramHeader,
+) -> Result<Option<Vma>, ElfError> {
+    let file_end = ph
+        .offset
+        .checked_add(ph.filesz)
+        .ok_or(ElfError::BadSegment)?;
+    if ph.filesz > ph.memsz || file_end > image.len() as u64 || !in_user_space(ph.vaddr, ph.memsz) {
+        return Err(ElfError::BadSegment);
+    }
+    if ph.memsz == 0 {
+        return Ok(None);
+    }
+
+    let writable = ph.flags & PF_W != 0;
+    let flags = USER | if writable { WRITABLE } else { 0 };
+    let start = ph.vaddr & !(PAGE_SIZE - 1);
+    let end = (ph.vaddr + ph.memsz).next_multiple_of(PAGE_SIZE);
+    for page in (start..end).step_by(PAGE_SIZE as usize) {
+        space.ensure_page(page, flags)?;
+    }
+    space.write(ph.vaddr, &image[ph.offset as usize..file_end as usize])?;
+
+    // fresh pages are zero, but the one holding the end of the file data
+    // may be shared with another segment
+    let bss = ph.vaddr + ph.filesz;
+    let bss_end = (ph.vaddr + ph.memsz).min(bss.next_multiple_of(PAGE_SIZE));
+    let zeros = [0u8; PAGE_SIZE as usize];
+    space.write(bss, &zeros[..(bss_end - bss) as usize])?;
+    Ok(Some(Vma {
+        start,
+        end,
+        prot: PROT_READ | PROT_EXEC | if writable { PROT_WRITE } else { 0 },
+        shared: false,
+        kind: VmaKind::Image,
+    }))
+}
+
+fn setup_stack(
+    space: &AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<u64, ElfError> {
+    let string_bytes: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    if argv.len() + envp.len() > MAX_ARGS || string_bytes > MAX_ARG_BYTES {
+        return Err(ElfError::TooManyArgs);
+    }
+    space.map_user_zeroed(STACK_TOP - STACK_SIZE, STACK_PAGES, true)?;
+
+    let mut sp = STACK_TOP;
+    let mut push_str = |s: &str| -> Result<u64, ElfError> {
+        sp -= s.len() as u64 + 1;
+        space.write(sp, s.as_bytes())?;
+        space.write(sp + s.len() as u64, &[0])?;
+        Ok(sp)
+    };
+    let argv_ptrs = argv
+        .iter()
+        .map(|s| push_str(s))
+        .collect::<Result<Vec<_>, _>>()?;
+    let envp_ptrs = envp
+        .iter()
+        .map(|s| push_str(s))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // AT_RANDOM: 16 bytes for stack protector and pointer guard seeds; the
+    // TSC is the best entropy there is for now
+    let random = time::rdtsc().rotate_left(17) ^ (sp.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    sp -= 16;
+    space.write(sp, &random.to_le_bytes())?;
+    space.write(
+        sp + 8,
+        &time::rdtsc()
+            .wrapping_mul(0xBF58_476D_1CE4_E5B9)
+            .to_le_bytes(),
+    )?;
+    let random_addr = sp;
+
+    let mut words = Vec::with_capacity(argv_ptrs.len() + envp_ptrs.len() + 2 * auxv.len() + 8);
+    words.push(argv_ptrs.len() as u64);
+    words.extend_from_slice(&argv_ptrs);
+    words.push(0);
+    words.extend_from_slice(&envp_ptrs);
+    words.push(0);
+    for &(key, value) in auxv.iter().chain(&[(AT_RANDOM, random_addr), (AT_NULL, 0)]) {
+        words.push(key);
+        words.push(value);
+    }
+
+    sp = (sp - words.len() as u64 * 8) & !15;
+    for (i, word) in words.iter().enumerate() {
+        space.write(sp + i as u64 * 8, &word.to_le_bytes())?;
+    }
+    Ok(sp)
+}
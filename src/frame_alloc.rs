@@ -1,9 +1,20 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+
 use crate::{
+    info,
     mb2::{self, Mb2MmapTag},
-    serial_println,
+    modules,
+    sync::irq_spinlock::IrqSpinLock,
+    warn,
 };
 
 pub const PAGE_SIZE: u64 = 4096;
+/// Boot modules the allocator steps around; any more just raise `min_start`.
+const MAX_RESERVED: usize = 8;
+
+// boot.S identity maps the low 4GiB with 2MiB pages; frames above it would
+// not be addressable.
+pub const IDENTITY_MAP_END: u64 = 4 << 30;
 
 fn align_up(x: u64, a: u64) -> u64 {
     (x + (a - 1)) & !(a - 1)
@@ -28,8 +39,18 @@ pub struct FrameAllocator {
     min_start: u64,
     _mb2_start: u64,
     _mb2_end: u64,
+    /// Page-aligned `start..end` ranges in use by boot modules.
+    reserved: [(u64, u64); MAX_RESERVED],
+    reserved_len: usize,
+
+    // freed frames, linked through their first 8 bytes
+    free_list: u64,
+    free_count: usize,
 }
 
+// Safety: the raw mmap pointer is only read, and only under FRAMES.
+unsafe impl Send for FrameAllocator {}
+
 impl FrameAllocator {
     pub fn init(mb2_info_phys: u64, kernel_start: u64, kernel_end: u64) -> Option<Self> {
         let mmap = mb2::get_mmap_tag(mb2_info_phys as usize)? as *const Mb2MmapTag;
@@ -48,7 +69,21 @@ impl FrameAllocator {
         let mb2_start = mb2_info_phys;
         let mb2_end = mb2_info_phys + mb2_info_total_size;
 
-        let min_start = align_up(core::cmp::max(kernel_end, mb2_end), PAGE_SIZE);
+        let mut min_start = align_up(kernel_end.max(mb2_end), PAGE_SIZE);
+
+        // boot modules stay in use for as long as processes map them
+        let mut reserved = [(0, 0); MAX_RESERVED];
+        let mut reserved_len = 0;
+        for (start, end) in modules::ranges(mb2_info_phys as usize) {
+            let range = (align_down(start, PAGE_SIZE), align_up(end, PAGE_SIZE));
+            if reserved_len < MAX_RESERVED {
+                reserved[reserved_len] = range;
+                reserved_len += 1;
+            } else {
+                warn!("too many modules, no RAM below {:#x} used", range.1);
+                min_start = min_start.max(range.1);
+            }
+        }
 
         let mut fa = Self {
             _mmap_ptr: mmap,
@@ -63,6 +98,10 @@ impl FrameAllocator {
             min_start,
             _mb2_start: mb2_start,
             _mb2_end: mb2_end,
+            reserved,
+            reserved_len,
+            free_list: 0,
+            free_count: 0,
         };
 
         fa.advance_to_next_usable_region();
@@ -71,6 +110,64 @@ impl FrameAllocator {
     }
 
     pub fn alloc_frame(&mut self) -> Option<u64> {
+        if self.free_list != 0 {
+            let frame = self.free_list;
+            self.free_list = unsafe { *(frame as *const u64) };
+            self.free_count -= 1;
+            return Some(frame);
+        }
+        self.alloc_fresh()
+    }
+
+    /// `count` physically contiguous frames, for things like stacks that need
+    /// more than a page. Only comes from never-used memory; the free list is
+    /// not searched.
+    pub fn alloc_contiguous(&mut self, count: usize) -> Option<u64> {
+        let size = count as u64 * PAGE_SIZE;
+        loop {
+            if self.cur_frame == 0 || self.cur_frame + size > self.cur_region_end {
+                // too little left here: keep the tail for single frames
+                while self.cur_frame != 0 && self.cur_frame < self.cur_region_end {
+                    let frame = self.cur_frame;
+                    self.cur_frame += PAGE_SIZE;
+                    if !self.frame_is_forbidden(frame) {
+                        self.free_frame(frame);
+                    }
+                }
+                if !self.advance_to_next_usable_region() {
+                    return None;
+                }
+                continue;
+            }
+
+            let start = self.cur_frame;
+            self.cur_frame += size;
+            let Some(bad) = (start..start + size)
+                .step_by(PAGE_SIZE as usize)
+                .filter(|&f| self.frame_is_forbidden(f))
+                .last()
+            else {
+                return Some(start);
+            };
+            // retry just past the last forbidden frame; what lies before it
+            // still serves single frames
+            for frame in (start..bad).step_by(PAGE_SIZE as usize) {
+                if !self.frame_is_forbidden(frame) {
+                    self.free_frame(frame);
+                }
+            }
+            self.cur_frame = bad + PAGE_SIZE;
+        }
+    }
+
+    pub fn free_frame(&mut self, frame: u64) {
+        debug_assert!(frame.is_multiple_of(PAGE_SIZE));
+        unsafe { *(frame as *mut u64) = self.free_list };
+        self.free_list = frame;
+        self.free_count += 1;
+    }
+
+    fn alloc_fresh(&mut self) -> Option<u64> {
         loop {
             if self.cur_frame == 0 || self.cur_frame >= self.cur_region_end {
                 if !self.advance_to_next_usable_region() {
@@ -95,7 +192,14 @@ impl FrameAllocator {
             return true;
         }
 
-        false
+        if self.reserved[..self.reserved_len]
+            .iter()
+            .any(|&(start, end)| (start..end).contains(&frame))
+        {
+            return true;
+        }
+
+        frame + PAGE_SIZE > IDENTITY_MAP_END
     }
 
     fn advance_to_next_usable_region(&mut self) -> bool {
@@ -129,13 +233,76 @@ impl FrameAllocator {
             self.cur_frame = region_start;
             self.cur_region_end = region_end;
 
-            serial_println!(
-                "FA: using region base={:#x}..{:#x}",
-                self.cur_frame,
-                self.cur_region_end
+            info!(
+                "using region base={:#x}..{:#x}",
+                self.cur_frame, self.cur_region_end
             );
             return true;
         }
         false
     }
 }
+
+static FRAMES: IrqSpinLock<Option<FrameAllocator>> = IrqSpinLock::new(None);
+
+const FRAME_COUNT: usize = (IDENTITY_MAP_END / PAGE_SIZE) as usize;
+
+/// References to each frame: 1 once allocated, plus one per extra page
+/// table entry sharing it (copy-on-write after fork). The frame goes back to
+/// the allocator when the count drops to 0.
+static REFS: [AtomicU16; FRAME_COUNT] = [const { AtomicU16::new(0) }; FRAME_COUNT];
+
+fn refs(frame: u64) -> &'static AtomicU16 {
+    &REFS[(frame / PAGE_SIZE) as usize]
+}
+
+/// Sets up the global allocator used by `alloc_frame` and friends.
+pub fn init(mb2_info_phys: u64, kernel_start: u64, kernel_end: u64) -> bool {
+    let fa = FrameAllocator::init(mb2_info_phys, kernel_start, kernel_end);
+    let ok = fa.is_some();
+    *FRAMES.lock() = fa;
+    ok
+}
+
+fn with_frames<R>(f: impl FnOnce(&mut FrameAllocator) -> Option<R>) -> Option<R> {
+    FRAMES.lock().as_mut().and_then(f)
+}
+
+pub fn alloc_frame() -> Option<u64> {
+    let frame = with_frames(|fa| fa.alloc_frame())?;
+    refs(frame).store(1, Ordering::Relaxed);
+    Some(frame)
+}
+
+pub fn alloc_contiguous(count: usize) -> Option<u64> {
+    let start = with_frames(|fa| fa.alloc_contiguous(count))?;
+    for i in 0..count as u64 {
+        refs(start + i * PAGE_SIZE).store(1, Ordering::Relaxed);
+    }
+    Some(start)
+}
+
+/// Takes another reference to an allocated frame.
+pub fn share(frame: u64) {
+    let old = refs(frame).fetch_add(1, Ordering::Relaxed);
+    assert!(old != 0 && old != u16::MAX, "bad frame reference count");
+}
+
+/// References held to `frame`; 1 means whoever asks owns it alone.
+pub fn ref_count(frame: u64) -> u16 {
+    refs(frame).load(Ordering::Relaxed)
+}
+
+/// Drops a reference to a frame from `alloc_frame` or `alloc_contiguous`
+/// (one page at a time), returning it to the allocator with the last one.
+pub fn free_frame(frame: u64) {
+    let old = refs(frame).fetch_sub(1, Ordering::AcqRel);
+    assert!(old != 0, "freeing free frame {:#x}", frame);
+    if old > 1 {
+        return;
+    }
+    with_frames(|fa| {
+        fa.free_frame(frame);
+        Some(())
+    });
+}
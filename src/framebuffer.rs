@@ -0,0 +1,344 @@
+use core::fmt;
+use core::ptr::write_volatile;
+
+use crate::ansi::{AnsiTarget, DEFAULT_ATTR, Parser, TermColor};
+use crate::console::{Console, register};
+use crate::frame_alloc::IDENTITY_MAP_END;
+use crate::mb2::{self, Mb2PaletteEntry};
+use crate::psf::Font;
+use crate::sync::irq_spinlock::IrqSpinLock;
+use crate::{info, warn};
+
+// Rasterized from DejaVu Sans Mono, CP437 glyph order with a unicode table.
+static FONT_DATA: &[u8] = include_bytes!("fonts/default8x16.psf");
+
+// Glyph used for anything the font can't draw (CP437 0xfe, like the VGA writer).
+const FALLBACK_GLYPH: usize = 0xfe;
+
+const TAB_WIDTH: usize = 8;
+
+pub static WRITER: IrqSpinLock<Option<Writer>> = IrqSpinLock::new(None);
+
+#[derive(Clone, Copy)]
+struct Channel {
+    shift: u8,
+    bits: u8,
+}
+
+impl Channel {
+    fn encode(self, v: u8) -> u32 {
+        if self.bits == 0 {
+            return 0;
+        }
+        let v = if self.bits >= 8 {
+            (v as u32) << (self.bits - 8)
+        } else {
+            (v as u32) >> (8 - self.bits)
+        };
+        v << self.shift
+    }
+}
+
+enum PixelFormat {
+    Rgb {
+        red: Channel,
+        green: Channel,
+        blue: Channel,
+    },
+    Indexed(&'static [Mb2PaletteEntry]),
+}
+
+impl PixelFormat {
+    fn encode(&self, r: u8, g: u8, b: u8) -> u32 {
+        match self {
+            PixelFormat::Rgb { red, green, blue } => {
+                red.encode(r) | green.encode(g) | blue.encode(b)
+            }
+            PixelFormat::Indexed(palette) => {
+                // nearest palette entry by squared distance
+                let dist = |e: &Mb2PaletteEntry| {
+                    let dr = e.red as i32 - r as i32;
+                    let dg = e.green as i32 - g as i32;
+                    let db = e.blue as i32 - b as i32;
+                    dr * dr + dg * dg + db * db
+                };
+                palette
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, e)| dist(e))
+                    .map(|(i, _)| i as u32)
+                    .unwrap_or(0)
+            }
+        }
+    }
+}
+
+pub struct Writer {
+    base: *mut u8,
+    pitch: usize,
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+    format: PixelFormat,
+    font: Font<'static>,
+    cols: usize,
+    rows: usize,
+    col: usize,
+    row: usize,
+    fg: u32,
+    bg: u32,
+    ansi: Parser,
+}
+
+// Safety: the framebuffer is only ever touched through the WRITER lock.
+unsafe impl Send for Writer {}
+
+impl Writer {
+    pub fn write_string(&mut self, s: &str) {
+        let mut ansi = core::mem::replace(&mut self.ansi, Parser::new(DEFAULT_ATTR));
+        for c in s.chars() {
+            ansi.feed(c, self);
+        }
+        self.ansi = ansi;
+    }
+
+    fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.new_line(),
+            '\r' => self.col = 0,
+            '\t' => {
+                let next = (self.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.col < next.min(self.cols) {
+                    self.draw_char(' ');
+                }
+            }
+            '\x08' => self.col = self.col.min(self.cols - 1).saturating_sub(1),
+            c => self.draw_char(c),
+        }
+    }
+
+    fn draw_char(&mut self, c: char) {
+        let glyph = if c.is_control() {
+            None
+        } else {
+            self.font.glyph_index(c)
+        };
+
+        if self.col >= self.cols {
+            self.new_line();
+        }
+        self.draw_glyph(glyph.unwrap_or(FALLBACK_GLYPH), self.col, self.row);
+        self.col += 1;
+    }
+
+    fn new_line(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+        self.scroll_up();
+    }
+
+    fn scroll_up(&mut self) {
+        let line_bytes = self.font.height * self.pitch;
+        unsafe {
+            core::ptr::copy(
+                self.base.add(line_bytes),
+                self.base,
+                (self.rows - 1) * line_bytes,
+            );
+        }
+        self.clear_row(self.rows - 1);
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        self.clear_cells(row, 0, self.cols);
+    }
+
+    fn clear_cells(&mut self, row: usize, from: usize, to: usize) {
+        let y0 = row * self.font.height;
+        for y in y0..y0 + self.font.height {
+            for x in from * self.font.width..to * self.font.width {
+                self.put_pixel(x, y, self.bg);
+            }
+        }
+    }
+
+    fn draw_glyph(&mut self, index: usize, col: usize, row: usize) {
+        let bitmap = self.font.glyph(index);
+        let stride = self.font.bytes_per_row();
+        let x0 = col * self.font.width;
+        let y0 = row * self.font.height;
+
+        for (dy, line) in bitmap.chunks_exact(stride).enumerate() {
+            for dx in 0..self.font.width {
+                let set = line[dx / 8] & (0x80 >> (dx % 8)) != 0;
+                let px = if set { self.fg } else { self.bg };
+                self.put_pixel(x0 + dx, y0 + dy, px);
+            }
+        }
+    }
+
+    fn put_pixel(&mut self, x: usize, y: usize, value: u32) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let off = y * self.pitch + x * self.bytes_per_pixel;
+        unsafe {
+            let p = self.base.add(off);
+            match self.bytes_per_pixel {
+                1 => write_volatile(p, value as u8),
+                2 => write_volatile(p as *mut u16, value as u16),
+                3 => {
+                    write_volatile(p, value as u8);
+                    write_volatile(p.add(1), (value >> 8) as u8);
+                    write_volatile(p.add(2), (value >> 16) as u8);
+                }
+                _ => write_volatile(p as *mut u32, value),
+            }
+        }
+    }
+}
+
+impl AnsiTarget for Writer {
+    fn size(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    fn cursor(&self) -> (usize, usize) {
+        (self.row, self.col.min(self.cols - 1))
+    }
+
+    fn set_cursor(&mut self, row: usize, col: usize) {
+        self.row = row;
+        self.col = col;
+    }
+
+    fn put(&mut self, c: char) {
+        self.write_char(c);
+    }
+
+    fn erase(&mut self, row: usize, from: usize, to: usize) {
+        self.clear_cells(row, from, to.min(self.cols));
+    }
+
+    fn set_colors(&mut self, fg: TermColor, bg: TermColor) {
+        let (r, g, b) = fg.rgb();
+        self.fg = self.format.encode(r, g, b);
+        let (r, g, b) = bg.rgb();
+        self.bg = self.format.encode(r, g, b);
+    }
+}
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+pub struct FramebufferConsole;
+
+pub static CONSOLE: FramebufferConsole = FramebufferConsole;
+
+impl Console for FramebufferConsole {
+    fn name(&self) -> &'static str {
+        "fb"
+    }
+
+    fn write_str(&self, s: &str) {
+        if let Some(w) = WRITER.lock().as_mut() {
+            w.write_string(s);
+        }
+    }
+}
+
+/// Sets up and registers the framebuffer console if the bootloader handed us
+/// a linear framebuffer. Returns false for EGA text mode (use `vga_buffer`) or anything
+/// we can't drive.
+pub fn init(mb2_info_phys: usize) -> bool {
+    let Some(tag) = mb2::get_framebuffer_tag(mb2_info_phys) else {
+        return false;
+    };
+
+    let format = if let Some(rgb) = tag.rgb_info() {
+        PixelFormat::Rgb {
+            red: Channel {
+                shift: rgb.red_field_position,
+                bits: rgb.red_mask_size,
+            },
+            green: Channel {
+                shift: rgb.green_field_position,
+                bits: rgb.green_mask_size,
+            },
+            blue: Channel {
+                shift: rgb.blue_field_position,
+                bits: rgb.blue_mask_size,
+            },
+        }
+    } else if let Some(palette) = tag.palette() {
+        PixelFormat::Indexed(palette)
+    } else {
+        return false;
+    };
+
+    let bytes_per_pixel = (tag.bpp as usize).div_ceil(8);
+    if !(1..=4).contains(&bytes_per_pixel) {
+        warn!("unsupported bpp {}", tag.bpp);
+        return false;
+    }
+
+    let addr = tag.addr;
+    let size = tag.pitch as u64 * tag.height as u64;
+    if addr.saturating_add(size) > IDENTITY_MAP_END {
+        warn!("framebuffer {:#x} is not identity mapped", addr);
+        return false;
+    }
+
+    let font = match Font::parse(FONT_DATA) {
+        Ok(f) => f,
+        Err(e) => {
+            warn!("bad built-in font: {:?}", e);
+            return false;
+        }
+    };
+
+    let width = tag.width as usize;
+    let height = tag.height as usize;
+    let mut console = Writer {
+        base: addr as *mut u8,
+        pitch: tag.pitch as usize,
+        width,
+        height,
+        bytes_per_pixel,
+        format,
+        cols: width / font.width,
+        rows: height / font.height,
+        font,
+        col: 0,
+        row: 0,
+        fg: 0,
+        bg: 0,
+        ansi: Parser::new(DEFAULT_ATTR),
+    };
+    let (fg, bg) = DEFAULT_ATTR.colors();
+    console.set_colors(fg, bg);
+
+    if console.cols == 0 || console.rows == 0 {
+        return false;
+    }
+
+    for row in 0..console.rows {
+        console.clear_row(row);
+    }
+
+    info!(
+        "console {}x{} cells ({}x{} px, {} bpp)",
+        console.cols, console.rows, width, height, tag.bpp
+    );
+
+    *WRITER.lock() = Some(console);
+    register(&CONSOLE);
+    true
+}
@@ -43,13 +43,26 @@ static mut TSS: Tss64 = Tss64::new();
 
 // GDT layout:
 // 0: null
-// 1: code (0x08)
-// 2: data (0x10)
-// 3-4: TSS descriptor (0x18 selector)
-static mut GDT: [u64; 5] = [0; 5];
+// 1: kernel code (0x08)
+// 2: kernel data (0x10)
+// 3: user data (0x18, DPL3)
+// 4: user code (0x20, DPL3)
+// 5-6: TSS descriptor (0x28 selector)
+//
+// The user pair is data-then-code because `sysret` loads SS from STAR+8 and
+// CS from STAR+16, with STAR pointing at the kernel data selector.
+static mut GDT: [u64; 7] = [0; 7];
 
 const GDT_CODE: u64 = 0x00AF9A000000FFFF;
 const GDT_DATA: u64 = 0x00AF92000000FFFF;
+const GDT_USER_DATA: u64 = 0x00AFF2000000FFFF;
+const GDT_USER_CODE: u64 = 0x00AFFA000000FFFF;
+
+pub const KERNEL_CS: u16 = 0x08;
+pub const KERNEL_DS: u16 = 0x10;
+pub const USER_DS: u16 = 0x18 | 3;
+pub const USER_CS: u16 = 0x20 | 3;
+const TSS_SELECTOR: u16 = 0x28;
 
 fn make_tss_descriptor(base: u64, limit: u32) -> (u64, u64) {
     // 64-bit TSS descriptor is 16 bytes split across two u64s.
@@ -75,20 +88,22 @@ pub fn init(stack_top: u64) {
             bytes.add(DF_STACK_SIZE) as u64 & !0xF
         };
         TSS.ist[0] = df_stack_top; // IST1
-        TSS.rsp[0] = stack_top; // RSP0 (future user->kernel transitions)
+        TSS.rsp[0] = stack_top; // RSP0, replaced per thread on every switch
 
         GDT[0] = 0;
         GDT[1] = GDT_CODE;
         GDT[2] = GDT_DATA;
+        GDT[3] = GDT_USER_DATA;
+        GDT[4] = GDT_USER_CODE;
 
         let tss_base = core::ptr::addr_of!(TSS) as u64;
         let tss_limit = (size_of::<Tss64>() - 1) as u32;
         let (tss_lo, tss_hi) = make_tss_descriptor(tss_base, tss_limit);
-        GDT[3] = tss_lo;
-        GDT[4] = tss_hi;
+        GDT[5] = tss_lo;
+        GDT[6] = tss_hi;
 
         let gdtr = DescriptorTablePointer {
-            limit: (size_of::<[u64; 5]>() - 1) as u16,
+            limit: (size_of::<[u64; 7]>() - 1) as u16,
             base: core::ptr::addr_of!(GDT) as u64,
         };
 
@@ -106,7 +121,15 @@ pub fn init(stack_top: u64) {
             options(nostack, preserves_flags)
         );
 
-        // Load Task Register with TSS selector (0x18 = entry 3).
-        core::arch::asm!("ltr ax", in("ax") 0x18u16, options(nostack, preserves_flags));
+        // Load Task Register with TSS selector (0x28 = entry 5).
+        core::arch::asm!("ltr ax", in("ax") TSS_SELECTOR, options(nostack, preserves_flags));
+    }
+}
+
+/// Stack the CPU switches to when an interrupt or exception arrives in
+/// ring 3. The scheduler points it at the incoming thread's kernel stack.
+pub fn set_kernel_stack(top: u64) {
+    unsafe {
+        (*core::ptr::addr_of_mut!(TSS)).rsp[0] = top;
     }
 }
@@ -1,6 +1,7 @@
 use core::alloc::{GlobalAlloc, Layout};
 
-use crate::{serial_println, sync::spinlock::SpinLock};
+use crate::sync::{mcs::McsLock, once::OnceCell};
+use crate::{error, irq};
 
 struct Bump {
     start: usize,
@@ -45,13 +46,13 @@ impl Bump {
 }
 
 pub struct KernelAlloc {
-    bump: SpinLock<Bump>,
+    bump: McsLock<Bump>,
 }
 
 impl KernelAlloc {
     pub const fn new() -> Self {
         Self {
-            bump: SpinLock::new(Bump::empty()),
+            bump: McsLock::new(Bump::empty()),
         }
     }
 
@@ -63,8 +64,9 @@ impl KernelAlloc {
 
 unsafe impl GlobalAlloc for KernelAlloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut b = self.bump.lock();
-        b.alloc(layout)
+        // interrupts off: IRQ handlers and tasklets allocate too, and must
+        // not spin on a lock held by the thread they interrupted
+        irq::without_interrupts(|| self.bump.lock().alloc(layout))
     }
     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
 }
@@ -72,9 +74,11 @@ unsafe impl GlobalAlloc for KernelAlloc {
 #[global_allocator]
 static ALLOC: KernelAlloc = KernelAlloc::new();
 
+static RANGE: OnceCell<(usize, usize)> = OnceCell::new();
+
 #[alloc_error_handler]
 fn alloc_error(layout: Layout) -> ! {
-    serial_println!("ALLOC ERROR: {:?}", layout);
+    error!("allocation failed: {:?}", layout);
     loop {
         unsafe {
             core::arch::asm!("hlt");
@@ -83,5 +87,14 @@ fn alloc_error(layout: Layout) -> ! {
 }
 
 pub fn init(heap_start: usize, heap_size: usize) {
+    if RANGE.set((heap_start, heap_start + heap_size)).is_err() {
+        panic!("heap initialized twice");
+    }
     ALLOC.init(heap_start, heap_size);
 }
+
+/// `start..end` of the kernel heap, once `init` has run.
+#[allow(dead_code)]
+pub fn range() -> Option<(usize, usize)> {
+    RANGE.get().copied()
+}
@@ -1,5 +1,7 @@
 use core::mem::size_of;
 
+use crate::{gdt, pic};
+
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
 struct IdtEntry {
@@ -28,7 +30,7 @@ impl IdtEntry {
     fn set_handler(&mut self, handler: unsafe extern "C" fn()) {
         let addr = handler as u64;
         self.offset_low = addr as u16;
-        self.selector = 0x08; // your 64-bit code segment selector
+        self.selector = gdt::KERNEL_CS;
         self.ist = 0; // no IST for now
         self.type_attr = 0x8E; // Present=1, DPL=0, Type=0xE (interrupt gate)
         self.offset_mid = (addr >> 16) as u16;
@@ -36,10 +38,16 @@ impl IdtEntry {
         self.zero = 0;
     }
 
+    /// Interrupt gate that ring 3 may invoke with `int`.
+    fn set_user_handler(&mut self, handler: unsafe extern "C" fn()) {
+        self.set_handler(handler);
+        self.type_attr = 0xEE; // Present=1, DPL=3, Type=0xE
+    }
+
     fn set_handler_with_ist(&mut self, handler: unsafe extern "C" fn(), ist_index: u8) {
         let addr = handler as u64;
         self.offset_low = addr as u16;
-        self.selector = 0x08;
+        self.selector = gdt::KERNEL_CS;
         self.ist = ist_index & 0x7; // 1..7 (0 means “don’t use IST”)
         self.type_attr = 0x8E;
         self.offset_mid = (addr >> 16) as u16;
@@ -56,23 +64,34 @@ struct Idtr {
 
 static mut IDT: [IdtEntry; 256] = [IdtEntry::missing(); 256];
 
+/// CPU exception vectors are 0..EXCEPTION_COUNT.
+const EXCEPTION_COUNT: usize = 32;
+
+const BREAKPOINT: usize = 3;
+const DOUBLE_FAULT: usize = 8;
+
 unsafe extern "C" {
-    fn isr_bp();
-    fn isr_ud();
-    fn isr_df();
-    fn isr_gp();
-    fn isr_pf();
+    static exception_stubs: [unsafe extern "C" fn(); EXCEPTION_COUNT];
+    static irq_stubs: [unsafe extern "C" fn(); crate::irq::IRQ_COUNT];
 }
 
 pub fn init() {
     unsafe {
         let idt_pointer: *mut IdtEntry = core::ptr::addr_of_mut!(IDT) as *mut IdtEntry;
 
-        (*idt_pointer.add(3)).set_handler(isr_bp);
-        (*idt_pointer.add(6)).set_handler(isr_ud);
-        (*idt_pointer.add(8)).set_handler_with_ist(isr_df, 1);
-        (*idt_pointer.add(13)).set_handler(isr_gp);
-        (*idt_pointer.add(14)).set_handler(isr_pf);
+        for (i, &stub) in exception_stubs.iter().enumerate() {
+            let entry = &mut *idt_pointer.add(i);
+            match i {
+                // `int3` from user mode reports and resumes like in the kernel
+                BREAKPOINT => entry.set_user_handler(stub),
+                DOUBLE_FAULT => entry.set_handler_with_ist(stub, 1),
+                _ => entry.set_handler(stub),
+            }
+        }
+
+        for (i, &stub) in irq_stubs.iter().enumerate() {
+            (*idt_pointer.add(pic::IRQ_BASE as usize + i)).set_handler(stub);
+        }
 
         let idtr = Idtr {
             limit: (size_of::<[IdtEntry; 256]>() - 1) as u16,
@@ -82,3 +101,48 @@ pub fn init() {
         core::arch::asm!("lidt [{}]", in(reg) &idtr, options(readonly, nostack, preserves_flags));
     }
 }
+
+/// Installs a software interrupt gate callable from user mode.
+pub fn set_user_gate(vector: u8, handler: unsafe extern "C" fn()) {
+    unsafe {
+        let idt_pointer: *mut IdtEntry = core::ptr::addr_of_mut!(IDT) as *mut IdtEntry;
+        (*idt_pointer.add(vector as usize)).set_user_handler(handler);
+    }
+}
+
+/// Mnemonic and name of each CPU exception, for reports.
+pub fn exception_name(vector: u64) -> &'static str {
+    match vector {
+        0 => "#DE Divide Error",
+        1 => "#DB Debug",
+        2 => "NMI",
+        3 => "#BP Breakpoint",
+        4 => "#OF Overflow",
+        5 => "#BR BOUND Range Exceeded",
+        6 => "#UD Invalid Opcode",
+        7 => "#NM Device Not Available",
+        8 => "#DF Double Fault (IST1)",
+        10 => "#TS Invalid TSS",
+        11 => "#NP Segment Not Present",
+        12 => "#SS Stack-Segment Fault",
+        13 => "#GP General Protection Fault",
+        14 => "#PF Page Fault",
+        16 => "#MF x87 Floating-Point Exception",
+        17 => "#AC Alignment Check",
+        18 => "#MC Machine Check",
+        19 => "#XM SIMD Floating-Point Exception",
+        20 => "#VE Virtualization Exception",
+        21 => "#CP Control Protection Exception",
+        28 => "#HV Hypervisor Injection Exception",
+        29 => "#VC VMM Communication Exception",
+        30 => "#SX Security Exception",
+        _ => "reserved exception",
+    }
+}
+
+/// Whether an exception raised in ring 3 is the program's own doing (a bad
+/// instruction or operand), so that killing the process deals with it.
+/// NMI, #DF, #MC and the rest are about the machine or the kernel.
+pub fn is_user_fault(vector: u64) -> bool {
+    matches!(vector, 0 | 1 | 3..=7 | 11..=13 | 16 | 17 | 19 | 21)
+}
@@ -0,0 +1,79 @@
+use crate::sync::rwlock::RwSpinLock;
+use crate::{cpu, pic, tasklet, thread};
+
+pub const IRQ_COUNT: usize = 16;
+
+pub type IrqHandler = fn();
+
+// read on every interrupt, written once per driver
+static HANDLERS: RwSpinLock<[Option<IrqHandler>; IRQ_COUNT]> = RwSpinLock::new([None; IRQ_COUNT]);
+
+/// Installs `handler` for `irq` and unmasks the line.
+pub fn register(irq: u8, handler: IrqHandler) {
+    without_interrupts(|| HANDLERS.write()[irq as usize] = Some(handler));
+    pic::unmask(irq);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_irq_handler(irq: u64) {
+    let irq = irq as u8;
+    if pic::is_spurious(irq) {
+        pic::spurious_eoi(irq);
+        return;
+    }
+
+    let handler = HANDLERS.read()[irq as usize];
+    if let Some(handler) = handler {
+        // handlers run on whatever thread they interrupted and must not block
+        // or switch away from it
+        cpu::preempt_disable();
+        handler();
+        cpu::preempt_enable();
+    }
+
+    pic::eoi(irq);
+    tasklet::run_pending();
+    thread::preempt_if_needed();
+}
+
+pub fn enable() {
+    unsafe { core::arch::asm!("sti", options(nomem, nostack)) };
+}
+
+pub fn disable() {
+    unsafe { core::arch::asm!("cli", options(nomem, nostack)) };
+}
+
+/// Whether RFLAGS.IF is set.
+pub fn enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        core::arch::asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags));
+    }
+    rflags & (1 << 9) != 0
+}
+
+/// Disables interrupts and returns whether they were enabled, for `restore`.
+pub fn save_and_disable() -> bool {
+    let was_enabled = enabled();
+    if was_enabled {
+        disable();
+    }
+    was_enabled
+}
+
+pub fn restore(was_enabled: bool) {
+    if was_enabled {
+        enable();
+    }
+}
+
+/// Runs `f` with interrupts disabled, restoring the previous state after.
+/// For data shared with IRQ handlers on this CPU; prefer an `IrqSpinLock`
+/// when the data sits behind a lock anyway.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let was_enabled = save_and_disable();
+    let r = f();
+    restore(was_enabled);
+    r
+}
@@ -0,0 +1,86 @@
+// PS/2 keyboard (scancode set 1). Only tracks Shift and handles the console
+// scrollback hotkeys and the F12 debug key for now. Scrolling redraws the
+// whole screen, so the IRQ handler only records it and a tasklet does the
+// work; F12 writes thread and lock statistics over serial, which is slow
+// enough that it goes to the workqueue.
+
+use core::sync::atomic::{AtomicIsize, Ordering};
+
+use crate::port::inb;
+use crate::sync::irq_spinlock::IrqSpinLock;
+use crate::tasklet::Tasklet;
+use crate::workqueue::Work;
+use crate::{console, irq, serial, sync, thread};
+
+const DATA_PORT: u16 = 0x60;
+const KEYBOARD_IRQ: u8 = 1;
+
+const EXTENDED_PREFIX: u8 = 0xE0;
+const RELEASE_BIT: u8 = 0x80;
+const LSHIFT: u8 = 0x2A;
+const RSHIFT: u8 = 0x36;
+const PAGE_UP: u8 = 0x49; // after 0xE0
+const PAGE_DOWN: u8 = 0x51; // after 0xE0
+const F12: u8 = 0x58;
+
+struct State {
+    extended: bool,
+    lshift: bool,
+    rshift: bool,
+}
+
+static STATE: IrqSpinLock<State> = IrqSpinLock::new(State {
+    extended: false,
+    lshift: false,
+    rshift: false,
+});
+
+/// Lines to scroll the view by, summed until `SCROLL` runs.
+static SCROLL_PENDING: AtomicIsize = AtomicIsize::new(0);
+static SCROLL: Tasklet = Tasklet::new(scroll, 0);
+static DUMP: Work = Work::new(dump_stats, 0);
+
+pub fn init() {
+    irq::register(KEYBOARD_IRQ, on_irq);
+}
+
+fn on_irq() {
+    let scancode = unsafe { inb(DATA_PORT) };
+
+    let mut st = STATE.lock();
+    if scancode == EXTENDED_PREFIX {
+        st.extended = true;
+        return;
+    }
+
+    let extended = core::mem::replace(&mut st.extended, false);
+    let released = scancode & RELEASE_BIT != 0;
+    let code = scancode & !RELEASE_BIT;
+
+    match (extended, code) {
+        (false, LSHIFT) => st.lshift = !released,
+        (false, RSHIFT) => st.rshift = !released,
+        (true, PAGE_UP | PAGE_DOWN) if !released && (st.lshift || st.rshift) => {
+            let page = console::page_lines() as isize;
+            SCROLL_PENDING.fetch_add(
+                if code == PAGE_UP { page } else { -page },
+                Ordering::Relaxed,
+            );
+            SCROLL.schedule();
+        }
+        (false, F12) if !released => DUMP.submit(),
+        _ => {}
+    }
+}
+
+fn scroll(_: usize) {
+    let lines = SCROLL_PENDING.swap(0, Ordering::Relaxed);
+    if lines != 0 {
+        console::scroll_view(lines);
+    }
+}
+
+fn dump_stats(_: usize) {
+    thread::dump(serial::_print);
+    sync::stats::dump(serial::_print);
+}
@@ -0,0 +1,360 @@
+// Leveled kernel log.
+//
+// Every record that passes the filter is kept in a fixed ring buffer (dmesg)
+// and printed to the registered consoles. The ring exists from the first
+// instruction, so messages logged before any console is up are replayed to
+// each console when it registers, and can be dumped after a panic.
+//
+// Command line:
+//   loglevel=<level>                  default max level (info)
+//   log=<module>:<level>[,...]        per-module overrides, prefix match
+//
+// Both are parsed once by `init`; until then everything up to info passes.
+
+use core::fmt::{self, Write};
+
+use crate::cmdline;
+use crate::console::Console;
+use crate::sync::irq_spinlock::IrqSpinLock;
+use crate::sync::once::OnceCell;
+use crate::{time, timer};
+
+const RING_RECORDS: usize = 256;
+const RECORD_TEXT: usize = 160;
+const CRATE_NAME: &str = "maizeOS";
+const FLUSH_MS: u64 = 1000;
+const MAX_RULES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => return None,
+        })
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Level::Error => "\x1b[91m",
+            Level::Warn => "\x1b[93m",
+            Level::Info => "\x1b[92m",
+            Level::Debug => "\x1b[96m",
+            Level::Trace => "\x1b[37m",
+        }
+    }
+}
+
+/// Truncating formatter into a fixed buffer; only ever cuts at char boundaries.
+#[derive(Clone, Copy)]
+struct FixedBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> FixedBuf<N> {
+    const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl<const N: usize> Write for FixedBuf<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(N - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Record {
+    tsc: u64,
+    level: Level,
+    target: &'static str,
+    text: FixedBuf<RECORD_TEXT>,
+}
+
+impl Record {
+    const EMPTY: Self = Self {
+        tsc: 0,
+        level: Level::Info,
+        target: "",
+        text: FixedBuf::new(),
+    };
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ns = time::tsc_to_ns(self.tsc);
+        write!(
+            f,
+            "[{:5}.{:06}] {} {}: {}",
+            ns / 1_000_000_000,
+            (ns / 1_000) % 1_000_000,
+            self.level.tag(),
+            self.target,
+            self.text.as_str()
+        )
+    }
+}
+
+struct Ring {
+    records: [Record; RING_RECORDS],
+    head: usize,
+    len: usize,
+    dropped: u64,
+    /// `dropped` as of the last `flush`.
+    reported: u64,
+}
+
+impl Ring {
+    fn push(&mut self, rec: Record) {
+        if self.len == RING_RECORDS {
+            self.dropped += 1;
+        }
+        self.records[self.head] = rec;
+        self.head = (self.head + 1) % RING_RECORDS;
+        self.len = (self.len + 1).min(RING_RECORDS);
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Record> {
+        let oldest = (self.head + RING_RECORDS - self.len) % RING_RECORDS;
+        (0..self.len).map(move |i| &self.records[(oldest + i) % RING_RECORDS])
+    }
+}
+
+static RING: IrqSpinLock<Ring> = IrqSpinLock::new(Ring {
+    records: [Record::EMPTY; RING_RECORDS],
+    head: 0,
+    len: 0,
+    dropped: 0,
+    reported: 0,
+});
+
+/// `maizeOS::mb2` -> `mb2`, the crate root itself -> `kernel`.
+fn short_target(module_path: &'static str) -> &'static str {
+    match module_path.strip_prefix(CRATE_NAME) {
+        Some("") => "kernel",
+        Some(rest) => rest.strip_prefix("::").unwrap_or(module_path),
+        None => module_path,
+    }
+}
+
+/// `loglevel=` and the `log=` rules, in command line order.
+struct Filter {
+    default: Level,
+    rules: [(&'static str, Level); MAX_RULES],
+    len: usize,
+}
+
+static FILTER: OnceCell<Filter> = OnceCell::new();
+
+/// Parses the log options off the command line. Needs `cmdline::init`.
+pub fn init() {
+    let mut filter = Filter {
+        default: cmdline::value("loglevel")
+            .and_then(Level::parse)
+            .unwrap_or(Level::Info),
+        rules: [("", Level::Info); MAX_RULES],
+        len: 0,
+    };
+    let mut skipped = 0;
+    for list in cmdline::values("log") {
+        for rule in list.split(',') {
+            let Some((module, level)) = rule.split_once(':') else {
+                continue;
+            };
+            let Some(level) = Level::parse(level) else {
+                continue;
+            };
+            if filter.len == MAX_RULES {
+                skipped += 1;
+                continue;
+            }
+            filter.rules[filter.len] = (module, level);
+            filter.len += 1;
+        }
+    }
+    if FILTER.set(filter).is_err() {
+        panic!("log initialized twice");
+    }
+    if skipped > 0 {
+        crate::warn!(
+            "log=: {} rules over the limit of {} ignored",
+            skipped,
+            MAX_RULES
+        );
+    }
+}
+
+/// Max level for `target`: the longest matching `log=` prefix, else `loglevel=`.
+fn max_level(target: &str) -> Level {
+    let Some(filter) = FILTER.get() else {
+        return Level::Info;
+    };
+    let mut best: Option<(usize, Level)> = None;
+    for &(module, level) in &filter.rules[..filter.len] {
+        let matches = target == module
+            || (target.starts_with(module) && target[module.len()..].starts_with("::"));
+        if matches && best.is_none_or(|(len, _)| module.len() >= len) {
+            best = Some((module.len(), level));
+        }
+    }
+    best.map_or(filter.default, |(_, level)| level)
+}
+
+fn enabled(level: Level, target: &'static str) -> bool {
+    level <= max_level(short_target(target))
+}
+
+pub fn _log(level: Level, target: &'static str, args: fmt::Arguments) {
+    if !enabled(level, target) {
+        return;
+    }
+
+    // format outside the ring lock: Display impls may log themselves
+    let mut rec = Record {
+        tsc: time::rdtsc(),
+        level,
+        target: short_target(target),
+        text: FixedBuf::new(),
+    };
+    let _ = rec.text.write_fmt(args);
+
+    RING.lock().push(rec);
+    print_record(&rec);
+}
+
+fn print_record(rec: &Record) {
+    let ns = time::tsc_to_ns(rec.tsc);
+    crate::println!(
+        "[{:5}.{:06}] {}{}\x1b[0m {}: {}",
+        ns / 1_000_000_000,
+        (ns / 1_000) % 1_000_000,
+        rec.level.color(),
+        rec.level.tag(),
+        rec.target,
+        rec.text.as_str()
+    );
+}
+
+/// Writes everything still in the ring to a console that just came up.
+pub fn replay(console: &dyn Console) {
+    let ring = RING.lock();
+    for rec in ring.iter() {
+        let mut line = FixedBuf::<{ RECORD_TEXT + 64 }>::new();
+        let _ = writeln!(line, "{}", rec);
+        console.write_str(line.as_str());
+    }
+}
+
+/// Housekeeping: reports records the ring overwrote since the last call,
+/// so losing part of dmesg does not go unnoticed.
+pub fn flush() {
+    let lost = {
+        let mut ring = RING.lock();
+        let lost = ring.dropped - ring.reported;
+        ring.reported = ring.dropped;
+        lost
+    };
+    if lost > 0 {
+        crate::warn!("{} records overwritten in the dmesg ring", lost);
+    }
+}
+
+/// Runs `flush` every FLUSH_MS from a kernel timer.
+pub fn start_flush() {
+    fn flush_tick(_: usize) {
+        flush();
+        arm();
+    }
+    fn arm() {
+        if timer::add_ms(FLUSH_MS, flush_tick, 0).is_none() {
+            crate::warn!("no timer free, dropped records will go unreported");
+        }
+    }
+    arm();
+}
+
+/// Dumps the ring with `emit`, for use after a panic. Does not wait for the
+/// ring lock: if the panicking code held it we would never get it back.
+pub fn dump(mut emit: impl FnMut(fmt::Arguments)) {
+    let Some(ring) = RING.try_lock() else {
+        emit(format_args!("dmesg: ring buffer busy, not dumped\n"));
+        return;
+    };
+    emit(format_args!(
+        "--- dmesg ({} records, {} dropped) ---\n",
+        ring.len, ring.dropped
+    ));
+    for rec in ring.iter() {
+        emit(format_args!("{}\n", rec));
+    }
+    emit(format_args!("--- end dmesg ---\n"));
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::log::_log($level, core::module_path!(), core::format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
@@ -2,14 +2,37 @@
 #![no_main]
 #![feature(alloc_error_handler)]
 
+mod ansi;
+mod cmdline;
+mod console;
+mod cpu;
+mod elf;
 mod frame_alloc;
+mod framebuffer;
 mod gdt;
 mod heap;
 mod idt;
+mod irq;
+mod keyboard;
+mod log;
 mod mb2;
+mod modules;
+mod page_fault;
+mod paging;
+mod pic;
+mod port;
+mod process;
+mod psf;
 mod serial;
 mod sync;
+mod syscall;
+mod tasklet;
+mod thread;
+mod time;
+mod timer;
+mod usermode;
 mod vga_buffer;
+mod workqueue;
 
 use core::arch::global_asm;
 use core::panic::PanicInfo;
@@ -19,15 +42,24 @@ extern crate alloc;
 use alloc::vec::Vec;
 
 use crate::frame_alloc::PAGE_SIZE;
-use crate::vga_buffer::print;
 
 global_asm!(include_str!("boot.S"));
 global_asm!(include_str!("interrupts.S"));
+global_asm!(include_str!("switch.S"));
+global_asm!(include_str!("syscall.S"));
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    serial::init();
-    serial_println!("KERNEL PANIC: {}", info);
+    // the report goes out through the lock-free emergency path; keep IRQ
+    // driven serial output from interleaving with it
+    irq::disable();
+    console::begin_panic();
+    match thread::try_current() {
+        Some((id, name)) => serial_println!("KERNEL PANIC in thread {} ({}): {}", id, name, info),
+        None => serial_println!("KERNEL PANIC: {}", info),
+    }
+    log::dump(serial::_print);
+    thread::dump(serial::_print);
     loop {
         unsafe {
             core::arch::asm!("hlt");
@@ -42,48 +74,57 @@ pub extern "C" fn rust_exception_handler(vector: u64, error: u64, frame_rip_ptr:
     let cs = unsafe { *frame_rip_ptr.add(1) };
     let rflags = unsafe { *frame_rip_ptr.add(2) };
 
+    if cs & 3 == 3 && idt::is_user_fault(vector) {
+        warn!(
+            "process {} killed: {} (error {:#x}) at rip {:#x}",
+            process::current().map_or(0, |pid| pid.as_u64()),
+            idt::exception_name(vector),
+            error,
+            rip
+        );
+        // processes have a single thread, so this ends the process
+        thread::exit();
+    }
+
+    // fatal: never returns, so treat it like a panic for console locking
+    console::begin_panic();
     serial_println!("");
     serial_println!("=== EXCEPTION ===");
     serial_println!("vector = {}  error = {:#x}", vector, error);
+    if let Some((id, name)) = thread::try_current() {
+        serial_println!("thread = {} ({})", id, name);
+    }
     serial_println!("RIP    = {:#016x}", rip);
     serial_println!("CS     = {:#x}", cs);
     serial_println!("RFLAGS = {:#016x}", rflags);
 
-    match vector {
-        3 => serial_println!("#BP Breakpoint"),
-        6 => serial_println!("#UD Invalid Opcode"),
-        8 => serial_println!("#DF Double Fault (IST1)"),
-        13 => serial_println!("#GP General Protection Fault"),
-        14 => {
-            serial_println!("#PF Page Fault");
-
-            let cr2: u64;
-            unsafe {
-                core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
-            }
-            serial_println!("CR2 (fault addr) = {:#016x}", cr2);
-
-            // Decode PF error code bits
-            let p = (error & (1 << 0)) != 0;
-            let wr = (error & (1 << 1)) != 0;
-            let us = (error & (1 << 2)) != 0;
-            let rsvd = (error & (1 << 3)) != 0;
-            let id = (error & (1 << 4)) != 0;
-
-            serial_println!(
-                "PF reason: {} | access={} | mode={} | rsvd={} | ifetch={}",
-                if p {
-                    "protection violation"
-                } else {
-                    "not-present page"
-                },
-                if wr { "write" } else { "read" },
-                if us { "user" } else { "kernel" },
-                rsvd,
-                id
-            );
+    serial_println!("{}", idt::exception_name(vector));
+    if vector == 14 {
+        let cr2: u64;
+        unsafe {
+            core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
         }
-        _ => {}
+        serial_println!("CR2 (fault addr) = {:#016x}", cr2);
+
+        // Decode PF error code bits
+        let p = (error & (1 << 0)) != 0;
+        let wr = (error & (1 << 1)) != 0;
+        let us = (error & (1 << 2)) != 0;
+        let rsvd = (error & (1 << 3)) != 0;
+        let id = (error & (1 << 4)) != 0;
+
+        serial_println!(
+            "PF reason: {} | access={} | mode={} | rsvd={} | ifetch={}",
+            if p {
+                "protection violation"
+            } else {
+                "not-present page"
+            },
+            if wr { "write" } else { "read" },
+            if us { "user" } else { "kernel" },
+            rsvd,
+            id
+        );
     }
 
     loop {
@@ -102,24 +143,38 @@ pub extern "C" fn rust_breakpoint_handler(frame_rip_ptr: *const u64) {
 }
 
 #[repr(C, align(8))]
-struct Multiboot2Header([u32; 6]);
+struct Multiboot2Header([u32; 12]);
 
 #[unsafe(link_section = ".multiboot2")]
 #[used]
 static MULTIBOOT2_HEADER: Multiboot2Header = Multiboot2Header({
     let magic: u32 = 0xe85250d6;
     let arch: u32 = 0;
-    let len: u32 = 24;
+    let len: u32 = 48;
     let csum: u32 = 0u32.wrapping_sub(magic.wrapping_add(arch).wrapping_add(len));
-    [magic, arch, len, csum, 0, 8]
+    // framebuffer tag (type 5, optional): width, height and depth 0 take
+    // whatever mode there is; padded to 8 bytes
+    let fb_tag: u32 = 5 | (1 << 16);
+    [magic, arch, len, csum, fb_tag, 20, 0, 0, 0, 0, 0, 8]
 });
 
 #[unsafe(no_mangle)]
 pub extern "C" fn rust_main(mb2_info: u32) -> ! {
+    // nothing is printed before the first console registers; the log ring
+    // keeps it until then
+    mb2::init(mb2_info as usize);
+    cmdline::init(mb2::info());
+    log::init();
+    time::init();
+
     serial::init();
-    serial_println!("maizeOS: entered rust_main");
-    serial_println!("mb2_info ptr = {:#x}", mb2_info);
-    mb2::dump(mb2_info as usize);
+    console::mem::init();
+
+    info!("entered rust_main");
+    info!("mb2_info ptr = {:#x}", mb2_info);
+    info!("cmdline: {:?}", cmdline::get());
+    info!("TSC {} MHz", time::tsc_hz() / 1_000_000);
+    mb2::dump(mb2::info());
 
     unsafe extern "C" {
         static __kernel_start: u8;
@@ -128,39 +183,29 @@ pub extern "C" fn rust_main(mb2_info: u32) -> ! {
 
     let kstart = unsafe { &__kernel_start as *const u8 as u64 };
     let kend = unsafe { &__kernel_end as *const u8 as u64 };
-    serial_println!("kernel range: {:#x}..{:#x}", kstart, kend);
+    info!("kernel range: {:#x}..{:#x}", kstart, kend);
 
-    let mut fa = frame_alloc::FrameAllocator::init(mb2_info as u64, kstart, kend)
-        .expect("failed to initialize FrameAllocator");
+    if !frame_alloc::init(mb2::info() as u64, kstart, kend) {
+        panic!("failed to initialize FrameAllocator");
+    }
 
+    // the bump heap needs one contiguous range
     const HEAP_PAGES: usize = 1024;
-    let mut heap_start: u64 = 0;
-
-    for i in 0..HEAP_PAGES {
-        let f = fa.alloc_frame().expect("out of frames");
-        if i == 0 {
-            heap_start = f;
-        } else {
-            // ensure contiguous (because bump heap assumes a contiguous range)
-            let expected = heap_start + (i as u64) * 4096;
-            if f != expected {
-                panic!(
-                    "heap frames not contiguous: got {:#x}, expected {:#x}",
-                    f, expected
-                );
-            }
-        }
-    }
+    let heap_start = frame_alloc::alloc_contiguous(HEAP_PAGES).expect("out of frames");
 
     let heap_size = HEAP_PAGES * PAGE_SIZE as usize;
     heap::init(heap_start as usize, heap_size);
-    serial_println!("heap: start={:#x} size={} bytes", heap_start, heap_size);
+    info!("heap: start={:#x} size={} bytes", heap_start, heap_size);
+    paging::init();
 
     let mut v = Vec::new();
     for i in 0..16 {
         v.push(i);
     }
-    serial_println!("heap test vec len={}", v.len());
+    debug!("heap test vec len={}", v.len());
+
+    thread::init();
+    workqueue::init();
 
     unsafe extern "C" {
         static stack_top: u8;
@@ -168,16 +213,50 @@ pub extern "C" fn rust_main(mb2_info: u32) -> ! {
 
     let stack_top_addr = unsafe { &stack_top as *const u8 as u64 };
     gdt::init(stack_top_addr);
-    serial_println!("GDT+TSS loaded (IST1 for #DF)");
+    info!("GDT+TSS loaded (IST1 for #DF)");
 
     idt::init();
-    serial_println!("IDT loaded (#BP/#UD/#DF/#GP/#PF)");
+    info!("IDT loaded (#BP/#UD/#DF/#GP/#PF)");
 
-    print("Welcome to MaizeOS");
+    syscall::init();
+    syscall::set_kernel_stack(stack_top_addr);
+    info!("SYSCALL/SYSRET and int 0x80 enabled");
 
-    loop {
-        unsafe {
-            core::arch::asm!("hlt");
-        }
+    if framebuffer::init(mb2::info()) {
+        info!("console: framebuffer");
+    } else {
+        vga_buffer::init();
+        info!("console: VGA text");
+    }
+
+    pic::init();
+    time::start_tick();
+    log::start_flush();
+    keyboard::init();
+    serial::init_irqs();
+    irq::enable();
+    info!("PIC remapped, timer, keyboard and serial IRQs enabled");
+
+    println!("Welcome to MaizeOS");
+
+    start_init();
+
+    // the idle thread takes over from here
+    thread::exit();
+}
+
+/// Runs the boot module named by `init=` (default `init`) as the first user
+/// program, if there is one.
+fn start_init() {
+    modules::log();
+    let name = cmdline::value("init").unwrap_or("init");
+    let Some(module) = modules::find(name) else {
+        info!("no {:?} module, no user space", name);
+        return;
+    };
+    let args: Vec<&str> = module.args.split_whitespace().collect();
+    match process::spawn(module.name, module.data, &args) {
+        Ok(pid) => info!("started {:?} as pid {}", module.name, pid),
+        Err(e) => warn!("cannot start {:?}: {:?}", module.name, e),
     }
 }
@@ -1,4 +1,7 @@
-use crate::serial_println;
+use crate::sync::once::OnceCell;
+use crate::{debug, error, info, warn};
+
+static INFO: OnceCell<usize> = OnceCell::new();
 
 #[repr(C)]
 struct Mb2InfoHeader {
@@ -29,66 +32,184 @@ pub struct Mb2MmapEntry {
     pub reserved: u32,
 }
 
+pub const FRAMEBUFFER_TYPE_INDEXED: u8 = 0;
+pub const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+
+#[repr(C)]
+pub struct Mb2FramebufferTag {
+    pub tag: Mb2TagHeader,
+    pub addr: u64,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    pub fb_type: u8,
+    pub reserved: u16,
+    //color_info
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Mb2RgbColorInfo {
+    pub red_field_position: u8,
+    pub red_mask_size: u8,
+    pub green_field_position: u8,
+    pub green_mask_size: u8,
+    pub blue_field_position: u8,
+    pub blue_mask_size: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Mb2PaletteEntry {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+/// A file loaded by the boot loader (`module2` in grub.cfg).
+#[repr(C)]
+pub struct Mb2ModuleTag {
+    pub tag: Mb2TagHeader,
+    pub mod_start: u32,
+    pub mod_end: u32,
+    //string
+}
+
+impl Mb2ModuleTag {
+    /// The arguments given after the path in grub.cfg; empty if not UTF-8.
+    pub fn cmdline(&self) -> &'static str {
+        let p = (self as *const Self as usize) + core::mem::size_of::<Self>();
+        let s = unsafe { core::ffi::CStr::from_ptr(p as *const core::ffi::c_char) };
+        s.to_str().unwrap_or("")
+    }
+}
+
+impl Mb2FramebufferTag {
+    /// Channel layout, only meaningful for `FRAMEBUFFER_TYPE_RGB`.
+    pub fn rgb_info(&self) -> Option<Mb2RgbColorInfo> {
+        if self.fb_type != FRAMEBUFFER_TYPE_RGB {
+            return None;
+        }
+        let p = (self as *const Self as usize) + core::mem::size_of::<Self>();
+        Some(unsafe { *(p as *const Mb2RgbColorInfo) })
+    }
+
+    /// Palette, only meaningful for `FRAMEBUFFER_TYPE_INDEXED`.
+    pub fn palette(&self) -> Option<&'static [Mb2PaletteEntry]> {
+        if self.fb_type != FRAMEBUFFER_TYPE_INDEXED {
+            return None;
+        }
+        // color_info = u16 num_colors; entries follow
+        let p = (self as *const Self as usize) + core::mem::size_of::<Self>();
+        let num_colors = unsafe { *(p as *const u16) } as usize;
+        let entries = (p + 2) as *const Mb2PaletteEntry;
+        Some(unsafe { core::slice::from_raw_parts(entries, num_colors) })
+    }
+}
+
+/// Records the boot information pointer handed over by the loader.
+pub fn init(mb2_info_phys: usize) {
+    if INFO.set(mb2_info_phys).is_err() {
+        warn!("boot information pointer already set");
+    }
+}
+
+/// The boot information pointer; `init` must have run.
+pub fn info() -> usize {
+    *INFO.get().expect("mb2::init has not run")
+}
+
 fn align_up_8(x: usize) -> usize {
     (x + 7) & !7
 }
 
 pub fn get_mmap_tag(mb2_info_phys: usize) -> Option<&'static Mb2MmapTag> {
+    let p = find_tag(mb2_info_phys, 6)?;
+    Some(unsafe { &*(p as *const Mb2MmapTag) })
+}
+
+pub fn get_framebuffer_tag(mb2_info_phys: usize) -> Option<&'static Mb2FramebufferTag> {
+    let p = find_tag(mb2_info_phys, 8)?;
+    Some(unsafe { &*(p as *const Mb2FramebufferTag) })
+}
+
+/// Boot command line (tag type 1); empty if it is not valid UTF-8.
+pub fn get_cmdline(mb2_info_phys: usize) -> Option<&'static str> {
+    let p = find_tag(mb2_info_phys, 1)?;
+    let s = unsafe { core::ffi::CStr::from_ptr((p + 8) as *const core::ffi::c_char) };
+    Some(s.to_str().unwrap_or(""))
+}
+
+/// Module tags (type 3), in the order the loader lists them.
+pub fn modules(mb2_info_phys: usize) -> impl Iterator<Item = &'static Mb2ModuleTag> {
+    tags(mb2_info_phys)
+        .filter(|&p| unsafe { (*(p as *const Mb2TagHeader)).mb_type } == 3)
+        .map(|p| unsafe { &*(p as *const Mb2ModuleTag) })
+}
+
+/// Returns the address of the first tag of type `mb_type`.
+fn find_tag(mb2_info_phys: usize, mb_type: u32) -> Option<usize> {
+    tags(mb2_info_phys).find(|&p| unsafe { (*(p as *const Mb2TagHeader)).mb_type } == mb_type)
+}
+
+/// Addresses of all tags up to the end tag.
+fn tags(mb2_info_phys: usize) -> impl Iterator<Item = usize> {
+    let mut p = 0;
+    let mut end = 0;
     if mb2_info_phys == 0 {
-        serial_println!("MB2: null pointer");
-        return None;
+        error!("null pointer");
+    } else {
+        let info = unsafe { &*(mb2_info_phys as *const Mb2InfoHeader) };
+        p = mb2_info_phys + core::mem::size_of::<Mb2InfoHeader>();
+        end = mb2_info_phys + info.total_size as usize;
     }
-    let info = unsafe { &*(mb2_info_phys as *const Mb2InfoHeader) };
-
-    let start = mb2_info_phys;
-    let end = start + info.total_size as usize;
 
-    let mut p = start + core::mem::size_of::<Mb2InfoHeader>();
-    while p + core::mem::size_of::<Mb2InfoHeader>() <= end {
+    core::iter::from_fn(move || {
+        if p + core::mem::size_of::<Mb2InfoHeader>() > end {
+            return None;
+        }
         let tag = unsafe { &*(p as *const Mb2TagHeader) };
 
         if tag.size < 8 {
-            serial_println!("MB2: ERROR tag size < 8 at {:#x}", p);
-            break;
+            error!("tag size < 8 at {:#x}", p);
+            return None;
         }
 
         if tag.mb_type == 0 && tag.size == 8 {
-            break;
-        }
-
-        if tag.mb_type == 6 {
-            return Some(unsafe { &*(p as *const Mb2MmapTag) });
+            return None;
         }
 
+        let here = p;
         let next = p + align_up_8(tag.size as usize);
 
         if next <= p {
-            serial_println!("MB2: ERROR tag pointer overflow");
-            break;
+            error!("tag pointer overflow");
+            end = 0;
         }
         p = next;
-    }
-    None
+        Some(here)
+    })
 }
 
 pub fn dump(mb2_info_phys: usize) {
     if mb2_info_phys == 0 {
-        serial_println!("MB2: null pointer");
+        error!("null pointer");
         return;
     }
 
     let info = unsafe { &*(mb2_info_phys as *const Mb2InfoHeader) };
 
-    serial_println!("MB2: info @ {:#x}", mb2_info_phys);
-    serial_println!("MB2: total size = {}", info.total_size);
-    serial_println!("MB2: reserved = {}", info.reserved);
+    info!("info @ {:#x}", mb2_info_phys);
+    info!("total size = {}", info.total_size);
+    info!("reserved = {}", info.reserved);
 
     if info.reserved != 0 {
-        serial_println!("MB2: WARNING reserved !=0 (unexpecteda)");
+        warn!("reserved !=0 (unexpected)");
     }
 
     if info.total_size < core::mem::size_of::<Mb2InfoHeader>() as u32 {
-        serial_println!("MB2: ERROR total size too small");
+        error!("total size too small");
         return;
     }
 
@@ -103,53 +224,56 @@ pub fn dump(mb2_info_phys: usize) {
         let tag = unsafe { &*(p as *const Mb2TagHeader) };
 
         if tag.size < 8 {
-            serial_println!("MB2: ERROR tag size < 8 at {:#x}", p);
+            error!("tag size < 8 at {:#x}", p);
             break;
         }
 
         if tag.mb_type == 0 && tag.size == 8 {
-            serial_println!("MB2: end tag");
+            debug!("end tag");
             break;
         }
 
-        serial_println!("MB2: tag typ={} size={} @ {:#x}", tag.mb_type, tag.size, p);
+        debug!("tag typ={} size={} @ {:#x}", tag.mb_type, tag.size, p);
 
         if tag.mb_type == 6 {
             saw_mmap = true;
             dump_mmap_tag(p, end);
         }
 
+        if tag.mb_type == 8 {
+            dump_framebuffer_tag(p);
+        }
+
         let next = p + align_up_8(tag.size as usize);
 
         if next <= p {
-            serial_println!("MB2: ERROR tag pointer overflow");
+            error!("tag pointer overflow");
             break;
         }
         p = next;
     }
     if !saw_mmap {
-        serial_println!("MB2: WARNING no memory map tag (type 6) found");
+        warn!("no memory map tag (type 6) found");
     }
 }
 
 fn dump_mmap_tag(tag_ptr: usize, info_end: usize) {
     let mmap = unsafe { &*(tag_ptr as *const Mb2MmapTag) };
 
-    serial_println!(
-        "MB2: mmap entry_size={} entry_version={}",
-        mmap.entry_size,
-        mmap.entry_version
+    info!(
+        "mmap entry_size={} entry_version={}",
+        mmap.entry_size, mmap.entry_version
     );
 
     let tag_size = mmap.tag.size as usize;
     if tag_ptr + tag_size > info_end {
-        serial_println!("MB2: ERROR mmap tag overruns info_end");
+        error!("mmap tag overruns info_end");
         return;
     }
 
     if mmap.entry_size < core::mem::size_of::<Mb2MmapEntry>() as u32 {
-        serial_println!(
-            "MB2: ERROR mmap entry_size {} < {}",
+        error!(
+            "mmap entry_size {} < {}",
             mmap.entry_size,
             core::mem::size_of::<Mb2MmapEntry>()
         );
@@ -174,16 +298,34 @@ fn dump_mmap_tag(tag_ptr: usize, info_end: usize) {
             _ => "OTHER",
         };
 
-        serial_println!(
-            "MB2: mmap[{:02}] base={:#016x} len={:#016x} type={} ({})",
-            idx,
-            ent.base_addr,
-            ent.length,
-            ent.entry_type,
-            kind
+        info!(
+            "mmap[{:02}] base={:#016x} len={:#016x} type={} ({})",
+            idx, ent.base_addr, ent.length, ent.entry_type, kind
         );
 
         idx += 1;
         e += mmap.entry_size as usize;
     }
 }
+
+fn dump_framebuffer_tag(tag_ptr: usize) {
+    let fb = unsafe { &*(tag_ptr as *const Mb2FramebufferTag) };
+    let (addr, pitch, width, height) = (fb.addr, fb.pitch, fb.width, fb.height);
+
+    info!(
+        "framebuffer addr={:#x} {}x{} pitch={} bpp={} type={}",
+        addr, width, height, pitch, fb.bpp, fb.fb_type
+    );
+
+    if let Some(rgb) = fb.rgb_info() {
+        info!(
+            "framebuffer rgb r={}:{} g={}:{} b={}:{}",
+            rgb.red_field_position,
+            rgb.red_mask_size,
+            rgb.green_field_position,
+            rgb.green_mask_size,
+            rgb.blue_field_position,
+            rgb.blue_mask_size
+        );
+    }
+}
@@ -0,0 +1,44 @@
+// Boot modules: files the boot loader puts in memory next to the kernel,
+// `module2 /boot/<file> <name> [args]` in grub.cfg. The first word after the
+// path names the module.
+
+use crate::frame_alloc::IDENTITY_MAP_END;
+use crate::{info, mb2, warn};
+
+pub struct Module {
+    pub name: &'static str,
+    /// Whatever follows the name in grub.cfg.
+    pub args: &'static str,
+    pub data: &'static [u8],
+}
+
+/// All modules we can read; ones beyond the identity map are skipped.
+pub fn iter() -> impl Iterator<Item = Module> {
+    mb2::modules(mb2::info()).filter_map(|tag| {
+        let (start, end) = (tag.mod_start as u64, tag.mod_end as u64);
+        let cmdline = tag.cmdline().trim_start();
+        let (name, args) = cmdline.split_once(' ').unwrap_or((cmdline, ""));
+        if end < start || end > IDENTITY_MAP_END {
+            warn!("module {:?} at {:#x}..{:#x} not usable", name, start, end);
+            return None;
+        }
+        let data =
+            unsafe { core::slice::from_raw_parts(start as *const u8, (end - start) as usize) };
+        Some(Module { name, args, data })
+    })
+}
+
+pub fn find(name: &str) -> Option<Module> {
+    iter().find(|m| m.name == name)
+}
+
+/// `start..end` of every module, so the frame allocator leaves them alone.
+pub fn ranges(mb2_info_phys: usize) -> impl Iterator<Item = (u64, u64)> {
+    mb2::modules(mb2_info_phys).map(|tag| (tag.mod_start as u64, tag.mod_end as u64))
+}
+
+pub fn log() {
+    for m in iter() {
+        info!("module {:?}: {} bytes", m.name, m.data.len());
+    }
+}
@@ -0,0 +1,52 @@
+// The page fault handler.
+//
+// User memory is populated lazily: a fault in a region the process has
+// mapped (see process::Vma) gets a zeroed frame and the access is retried,
+// and a fault just below the stack grows it. Anything else a user program
+// does wrong kills it. Kernel code never faults on purpose: system calls
+// fault user buffers in before touching them, so a kernel fault is fatal.
+
+use crate::{process, thread, warn};
+
+/// Error code bits.
+const PF_PRESENT: u64 = 1 << 0;
+const PF_WRITE: u64 = 1 << 1;
+const PF_USER: u64 = 1 << 2;
+
+fn read_cr2() -> u64 {
+    let cr2: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags))
+    };
+    cr2
+}
+
+/// Called from `isr_pf` with interrupts off; `frame` points at the saved
+/// RIP. Returns to retry the access.
+#[unsafe(no_mangle)]
+extern "C" fn rust_page_fault(error: u64, frame: *const u64) {
+    let addr = read_cr2();
+    if error & PF_USER == 0 {
+        crate::rust_exception_handler(14, error, frame);
+    }
+    let write = error & PF_WRITE != 0;
+    if process::with_current(|p| p.resolve_fault(addr, write)) == Some(true) {
+        return;
+    }
+
+    let rip = unsafe { *frame };
+    warn!(
+        "process {} killed: {} {} at {:#x}, rip {:#x}",
+        process::current().map_or(0, |pid| pid.as_u64()),
+        if write { "write" } else { "read" },
+        if error & PF_PRESENT != 0 {
+            "not allowed"
+        } else {
+            "of unmapped memory"
+        },
+        addr,
+        rip
+    );
+    // processes have a single thread, so this ends the process
+    thread::exit();
+}
@@ -0,0 +1,556 @@
+// 4-level page tables.
+//
+// boot.S identity maps the low 4GiB through PML4 entry 0 with 2MiB
+// supervisor pages; the kernel lives there and every page table frame comes
+// from the frame allocator below 4GiB, so tables are accessed through their
+// physical address. User space starts at the next PML4 entry and is mapped
+// with 4KiB pages, in an address space of its own: each has a PML4 whose
+// entry 0 is shared with the boot tables, so the kernel looks the same from
+// all of them.
+//
+// `fork` shares every user frame between the two spaces. Private pages turn
+// read-only with the COW bit on both sides; the first write faults and
+// `ensure_page` gives the writer a copy, or the frame itself once nobody
+// else holds it (see frame_alloc's reference counts).
+//
+// Unmapping or write-protecting pages is a TLB shootdown: entries are
+// changed first, then the TLB is flushed, and only then are frames freed, so
+// no stale translation can reach a frame that has found another use. There
+// is one CPU so far; the others would get their flush IPI at the same point.
+//
+// With PCIDs (CR4.PCIDE) every user address space gets its own TLB tag, so a
+// switch keeps the TLB entries of the others. Changing an address space that
+// is not loaded leaves entries of its tag stale: it is marked and the next
+// switch to it flushes that tag.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::frame_alloc::{self, PAGE_SIZE};
+use crate::sync::irq_spinlock::IrqSpinLock;
+use crate::{info, sync::once::OnceCell};
+
+pub const PRESENT: u64 = 1 << 0;
+pub const WRITABLE: u64 = 1 << 1;
+pub const USER: u64 = 1 << 2;
+const HUGE: u64 = 1 << 7;
+/// Software bit: read-only only until written, then copied.
+const COW: u64 = 1 << 9;
+/// Software bit: a MAP_SHARED page, which `fork` shares as it is.
+pub const SHARED: u64 = 1 << 10;
+/// Above this many pages a range flush drops the whole TLB instead.
+const FLUSH_ALL_PAGES: u64 = 32;
+/// Frames `unmap_range` collects before it flushes and frees them.
+const UNMAP_BATCH: usize = 64;
+const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const PCID_COUNT: usize = 4096;
+const PCID_MASK: u64 = PCID_COUNT as u64 - 1;
+/// CR3 bit 63: keep the TLB entries of the new PCID.
+const CR3_NOFLUSH: u64 = 1 << 63;
+const CR4_PCIDE: u64 = 1 << 17;
+const CPUID_PCID: u32 = 1 << 17;
+
+/// First address of user space: PML4 entry 1, clear of the identity map.
+pub const USER_BASE: u64 = 0x80_0000_0000;
+/// End of user space: the lower canonical half minus its top page, so a
+/// `syscall` at the very end cannot leave a non-canonical return address for
+/// `sysret` (which would fault in ring 0).
+pub const USER_END: u64 = 0x7FFF_FFFF_F000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    OutOfFrames,
+    AlreadyMapped,
+    /// The address is covered by a 2MiB page of the identity map.
+    HugePage,
+    NotMapped,
+}
+
+/// Serializes page table updates.
+static TABLES: IrqSpinLock<()> = IrqSpinLock::new(());
+
+/// The boot tables, loaded while kernel threads run.
+static KERNEL: OnceCell<AddressSpace> = OnceCell::new();
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+/// PCIDs in use, one bit each; 0 is the kernel's.
+static PCID_USED: IrqSpinLock<[u64; PCID_COUNT / 64]> = IrqSpinLock::new([0; PCID_COUNT / 64]);
+/// PCIDs whose TLB entries may be out of date.
+static PCID_STALE: [AtomicU64; PCID_COUNT / 64] = [const { AtomicU64::new(0) }; PCID_COUNT / 64];
+
+/// Records the boot tables and turns on PCIDs if the CPU has them.
+pub fn init() {
+    let _ = KERNEL.set(AddressSpace::current());
+    let ecx = core::arch::x86_64::__cpuid(1).ecx;
+    if ecx & CPUID_PCID != 0 {
+        unsafe {
+            let mut cr4: u64;
+            core::arch::asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+            cr4 |= CR4_PCIDE;
+            core::arch::asm!("mov cr4, {}", in(reg) cr4, options(nostack, preserves_flags));
+        }
+        PCID_ENABLED.store(true, Ordering::Relaxed);
+    }
+    info!(
+        "PCID {}",
+        if PCID_ENABLED.load(Ordering::Relaxed) {
+            "enabled"
+        } else {
+            "not supported"
+        }
+    );
+}
+
+fn alloc_pcid() -> u16 {
+    if !PCID_ENABLED.load(Ordering::Relaxed) {
+        return 0;
+    }
+    let mut used = PCID_USED.lock();
+    for (i, word) in used.iter_mut().enumerate() {
+        let free = !*word & if i == 0 { !1 } else { !0 };
+        if free != 0 {
+            let bit = free.trailing_zeros() as usize;
+            *word |= 1 << bit;
+            let pcid = i * 64 + bit;
+            // a previous owner may have left entries behind
+            mark_stale(pcid as u16);
+            return pcid as u16;
+        }
+    }
+    // all tags taken: share the kernel's and flush on every switch
+    0
+}
+
+fn free_pcid(pcid: u16) {
+    if pcid != 0 {
+        PCID_USED.lock()[pcid as usize / 64] &= !(1 << (pcid % 64));
+    }
+}
+
+fn mark_stale(pcid: u16) {
+    PCID_STALE[pcid as usize / 64].fetch_or(1 << (pcid % 64), Ordering::Relaxed);
+}
+
+/// Clears the stale mark and reports whether it was set.
+fn take_stale(pcid: u16) -> bool {
+    let bit = 1 << (pcid % 64);
+    PCID_STALE[pcid as usize / 64].fetch_and(!bit, Ordering::Relaxed) & bit != 0
+}
+
+fn read_cr3() -> u64 {
+    let cr3: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags))
+    };
+    cr3
+}
+
+fn invlpg(virt: u64) {
+    unsafe { core::arch::asm!("invlpg [{}]", in(reg) virt, options(nostack, preserves_flags)) };
+}
+
+fn table(phys: u64) -> &'static mut [u64; 512] {
+    unsafe { &mut *(phys as *mut [u64; 512]) }
+}
+
+fn index(virt: u64, level: u32) -> usize {
+    ((virt >> (12 + 9 * level)) & 0x1FF) as usize
+}
+
+/// A set of page tables, named by its PML4 frame, and its PCID. A plain
+/// handle: whoever creates one decides when it goes away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressSpace {
+    pml4: u64,
+    pcid: u16,
+}
+
+impl AddressSpace {
+    /// The address space the CPU is running in.
+    pub fn current() -> Self {
+        let cr3 = read_cr3();
+        Self {
+            pml4: cr3 & ADDR_MASK,
+            pcid: (cr3 & PCID_MASK) as u16,
+        }
+    }
+
+    /// The boot tables; `init` must have run.
+    pub fn kernel() -> Self {
+        *KERNEL.get().expect("paging::init has not run")
+    }
+
+    /// An empty user half on top of the shared kernel mappings.
+    pub fn new() -> Result<Self, MapError> {
+        let pml4 = frame_alloc::alloc_frame().ok_or(MapError::OutOfFrames)?;
+        let kernel = table(Self::kernel().pml4);
+        let t = table(pml4);
+        t.fill(0);
+        t[..index(USER_BASE, 3)].copy_from_slice(&kernel[..index(USER_BASE, 3)]);
+        Ok(Self {
+            pml4,
+            pcid: alloc_pcid(),
+        })
+    }
+
+    /// Frees the user half: every page table and every frame mapped there.
+    /// Must not be active on any CPU.
+    pub fn destroy(self) {
+        assert!(!self.is_active(), "destroying the active address space");
+        let _tables = TABLES.lock();
+        let pml4 = table(self.pml4);
+        for &pdpt in pml4[index(USER_BASE, 3)..256]
+            .iter()
+            .filter(|&&e| e & PRESENT != 0)
+        {
+            free_table(pdpt & ADDR_MASK, 2);
+        }
+        frame_alloc::free_frame(self.pml4);
+        free_pcid(self.pcid);
+    }
+
+    #[allow(dead_code)]
+    pub fn pcid(&self) -> u16 {
+        self.pcid
+    }
+
+    /// A copy of the user half that shares every frame, copy-on-write unless
+    /// the page is SHARED. Read-only private pages are marked too, so making
+    /// them writable later (mprotect) still copies first.
+    pub fn fork(&self) -> Result<Self, MapError> {
+        let child = Self::new()?;
+        let result = {
+            let _tables = TABLES.lock();
+            let (src, dst) = (table(self.pml4), table(child.pml4));
+            src[index(USER_BASE, 3)..256]
+                .iter_mut()
+                .zip(&mut dst[index(USER_BASE, 3)..256])
+                .filter(|(s, _)| **s & PRESENT != 0)
+                .try_for_each(|(s, d)| fork_table(s, d, 2))
+        };
+        // pages of ours just became read-only
+        self.flush_all();
+        match result {
+            Ok(()) => Ok(child),
+            Err(e) => {
+                child.destroy();
+                Err(e)
+            }
+        }
+    }
+
+    /// An existing entry changed: drop what the TLB may hold for `virt`.
+    fn flush_page(&self, virt: u64) {
+        if self.is_active() {
+            invlpg(virt);
+        } else {
+            mark_stale(self.pcid);
+        }
+    }
+
+    /// Entries for `start..end` changed: flush them on every CPU.
+    fn shootdown(&self, start: u64, end: u64) {
+        if !self.is_active() {
+            mark_stale(self.pcid);
+        } else if (end - start) / PAGE_SIZE > FLUSH_ALL_PAGES {
+            self.flush_all();
+        } else {
+            (start..end).step_by(PAGE_SIZE as usize).for_each(invlpg);
+        }
+    }
+
+    /// Many entries changed: drop everything the TLB holds for this space.
+    fn flush_all(&self) {
+        if self.is_active() {
+            // without NOFLUSH, loading CR3 drops the entries of its PCID
+            let mut cr3 = self.pml4;
+            if PCID_ENABLED.load(Ordering::Relaxed) {
+                cr3 |= self.pcid as u64;
+            }
+            unsafe {
+                core::arch::asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags))
+            };
+        } else {
+            mark_stale(self.pcid);
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        read_cr3() & ADDR_MASK == self.pml4
+    }
+
+    /// The page table entry for `virt`, creating missing intermediate tables
+    /// if `create` is set.
+    fn walk(
+        &self,
+        virt: u64,
+        create: bool,
+        flags: u64,
+    ) -> Result<Option<&'static mut u64>, MapError> {
+        let mut phys = self.pml4;
+        for level in (1..4).rev() {
+            let entry = &mut table(phys)[index(virt, level)];
+            if *entry & PRESENT == 0 {
+                if !create {
+                    return Ok(None);
+                }
+                let frame = frame_alloc::alloc_frame().ok_or(MapError::OutOfFrames)?;
+                table(frame).fill(0);
+                *entry = frame | PRESENT | WRITABLE | (flags & USER);
+            } else if *entry & HUGE != 0 {
+                return Err(MapError::HugePage);
+            } else {
+                // intermediate entries must be at least as permissive as the leaf
+                *entry |= flags & USER;
+            }
+            phys = *entry & ADDR_MASK;
+        }
+        Ok(Some(&mut table(phys)[index(virt, 0)]))
+    }
+
+    /// Maps the 4KiB page at `virt` to frame `phys`.
+    pub fn map(&self, virt: u64, phys: u64, flags: u64) -> Result<(), MapError> {
+        let _tables = TABLES.lock();
+        let entry = self.walk(virt, true, flags)?.ok_or(MapError::OutOfFrames)?;
+        if *entry & PRESENT != 0 {
+            return Err(MapError::AlreadyMapped);
+        }
+        *entry = (phys & ADDR_MASK) | flags | PRESENT;
+        Ok(())
+    }
+
+    /// The frame behind the page at `virt`, mapping a zeroed one first if
+    /// there is none. An existing mapping gains `flags` (e.g. WRITABLE); a
+    /// copy-on-write page made writable gets a frame of its own.
+    pub fn ensure_page(&self, virt: u64, flags: u64) -> Result<u64, MapError> {
+        let _tables = TABLES.lock();
+        let entry = self.walk(virt, true, flags)?.ok_or(MapError::OutOfFrames)?;
+        if *entry & COW != 0 && flags & WRITABLE != 0 {
+            let old = *entry & ADDR_MASK;
+            let frame = if frame_alloc::ref_count(old) == 1 {
+                old
+            } else {
+                let frame = frame_alloc::alloc_frame().ok_or(MapError::OutOfFrames)?;
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        old as *const u8,
+                        frame as *mut u8,
+                        PAGE_SIZE as usize,
+                    )
+                };
+                frame_alloc::free_frame(old);
+                frame
+            };
+            *entry = frame | (*entry & !(ADDR_MASK | COW)) | flags;
+            self.flush_page(virt);
+            return Ok(frame);
+        }
+        if *entry & PRESENT != 0 {
+            if *entry & flags != flags {
+                *entry |= flags;
+                self.flush_page(virt);
+            }
+            return Ok(*entry & ADDR_MASK);
+        }
+        let frame = frame_alloc::alloc_frame().ok_or(MapError::OutOfFrames)?;
+        table(frame).fill(0);
+        *entry = frame | flags | PRESENT;
+        Ok(frame)
+    }
+
+    /// Removes the mapping of `virt` and returns the frame it pointed at.
+    #[allow(dead_code)]
+    pub fn unmap(&self, virt: u64) -> Option<u64> {
+        let _tables = TABLES.lock();
+        let entry = self.walk(virt, false, 0).ok()??;
+        if *entry & PRESENT == 0 {
+            return None;
+        }
+        let phys = *entry & ADDR_MASK;
+        *entry = 0;
+        self.flush_page(virt);
+        Some(phys)
+    }
+
+    /// Calls `f` with the address and page table entry of every page in
+    /// `start..end` (page aligned) that maps something. Takes TABLES.
+    fn for_each_mapped(&self, start: u64, end: u64, mut f: impl FnMut(u64, &mut u64)) {
+        let _tables = TABLES.lock();
+        let mut page = start;
+        'pages: while page < end {
+            let mut phys = self.pml4;
+            for level in (1..4).rev() {
+                let entry = table(phys)[index(page, level)];
+                if entry & PRESENT == 0 || entry & HUGE != 0 {
+                    // no table below: skip all this entry would cover
+                    page = (page + 1).next_multiple_of(PAGE_SIZE << (9 * level));
+                    continue 'pages;
+                }
+                phys = entry & ADDR_MASK;
+            }
+            let entry = &mut table(phys)[index(page, 0)];
+            if *entry & PRESENT != 0 {
+                f(page, entry);
+            }
+            page += PAGE_SIZE;
+        }
+    }
+
+    /// Removes every mapping in `start..end` (page aligned) and drops the
+    /// frames once no TLB can reach them any more, a batch at a time.
+    pub fn unmap_range(&self, start: u64, end: u64) {
+        let mut frames = [0; UNMAP_BATCH];
+        let mut n = 0;
+        let mut batch_start = start;
+        self.for_each_mapped(start, end, |page, entry| {
+            if n == UNMAP_BATCH {
+                self.shootdown(batch_start, page);
+                frames.iter().for_each(|&f| frame_alloc::free_frame(f));
+                n = 0;
+                batch_start = page;
+            }
+            frames[n] = *entry & ADDR_MASK;
+            n += 1;
+            *entry = 0;
+        });
+        self.shootdown(batch_start, end);
+        frames[..n].iter().for_each(|&f| frame_alloc::free_frame(f));
+    }
+
+    /// Gives the pages mapped in `start..end` (page aligned) the USER and
+    /// WRITABLE bits of `flags`; copy-on-write pages stay read-only until
+    /// written.
+    pub fn protect_range(&self, start: u64, end: u64, flags: u64) {
+        self.for_each_mapped(start, end, |_, entry| {
+            let mut add = flags & (USER | WRITABLE);
+            if *entry & COW != 0 {
+                add &= !WRITABLE;
+            }
+            *entry = (*entry & !(USER | WRITABLE)) | add;
+        });
+        self.shootdown(start, end);
+    }
+
+    /// Physical address `virt` maps to, if any.
+    pub fn translate(&self, virt: u64) -> Option<u64> {
+        if virt < frame_alloc::IDENTITY_MAP_END {
+            return Some(virt);
+        }
+        let _tables = TABLES.lock();
+        let entry = self.walk(virt, false, 0).ok()??;
+        (*entry & PRESENT != 0).then_some((*entry & ADDR_MASK) | (virt & (PAGE_SIZE - 1)))
+    }
+
+    /// Whether user mode may access all of `start..start + len`, and write
+    /// to it if `write` is set.
+    pub fn user_accessible(&self, start: u64, len: u64, write: bool) -> bool {
+        let Some(end) = start.checked_add(len) else {
+            return false;
+        };
+        if start < USER_BASE || end > USER_END {
+            return false;
+        }
+        let need = PRESENT | USER | if write { WRITABLE } else { 0 };
+        let _tables = TABLES.lock();
+        let mut page = start & !(PAGE_SIZE - 1);
+        while page < end {
+            match self.walk(page, false, 0) {
+                Ok(Some(entry)) if *entry & need == need => {}
+                _ => return false,
+            }
+            page += PAGE_SIZE;
+        }
+        true
+    }
+
+    /// Allocates zeroed frames and maps them at `start..start + pages *
+    /// 4KiB` for user mode.
+    pub fn map_user_zeroed(
+        &self,
+        start: u64,
+        pages: usize,
+        writable: bool,
+    ) -> Result<(), MapError> {
+        let flags = USER | if writable { WRITABLE } else { 0 };
+        for i in 0..pages as u64 {
+            let frame = frame_alloc::alloc_frame().ok_or(MapError::OutOfFrames)?;
+            table(frame).fill(0);
+            if let Err(e) = self.map(start + i * PAGE_SIZE, frame, flags) {
+                frame_alloc::free_frame(frame);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies `bytes` to `virt` through the physical frames, so it works on
+    /// an address space that is not active. The pages must be mapped.
+    pub fn write(&self, virt: u64, bytes: &[u8]) -> Result<(), MapError> {
+        let mut done = 0;
+        while done < bytes.len() {
+            let addr = virt + done as u64;
+            let phys = self.translate(addr).ok_or(MapError::NotMapped)?;
+            let room = (PAGE_SIZE - (addr & (PAGE_SIZE - 1))) as usize;
+            let n = room.min(bytes.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(bytes[done..].as_ptr(), phys as *mut u8, n);
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Switches the CPU to this address space.
+    pub fn activate(&self) {
+        if self.is_active() {
+            return;
+        }
+        let mut cr3 = self.pml4;
+        if PCID_ENABLED.load(Ordering::Relaxed) {
+            cr3 |= self.pcid as u64;
+            // tag 0 is shared by the kernel and any overflow, so never trusted
+            if self.pcid != 0 && !take_stale(self.pcid) {
+                cr3 |= CR3_NOFLUSH;
+            }
+        }
+        unsafe { core::arch::asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags)) };
+    }
+}
+
+/// Fills the empty entry `dst` with a copy of the table `src` points at (a
+/// level `level` table, 0 = PT) and everything below it, sharing the frames
+/// mapped at the bottom.
+fn fork_table(src: &mut u64, dst: &mut u64, level: u32) -> Result<(), MapError> {
+    let copy = frame_alloc::alloc_frame().ok_or(MapError::OutOfFrames)?;
+    table(copy).fill(0);
+    *dst = copy | (*src & !ADDR_MASK);
+    let (from, to) = (table(*src & ADDR_MASK), table(copy));
+    for (s, d) in from.iter_mut().zip(to.iter_mut()) {
+        if *s & PRESENT == 0 {
+            continue;
+        }
+        if level > 0 {
+            fork_table(s, d, level - 1)?;
+            continue;
+        }
+        if *s & SHARED == 0 {
+            *s = (*s & !WRITABLE) | COW;
+        }
+        frame_alloc::share(*s & ADDR_MASK);
+        *d = *s;
+    }
+    Ok(())
+}
+
+/// Frees the table at `phys` (a level `level` table, 0 = PT) and everything
+/// below it.
+fn free_table(phys: u64, level: u32) {
+    for &entry in table(phys).iter().filter(|&&e| e & PRESENT != 0) {
+        if level == 0 {
+            frame_alloc::free_frame(entry & ADDR_MASK);
+        } else {
+            free_table(entry & ADDR_MASK, level - 1);
+        }
+    }
+    frame_alloc::free_frame(phys);
+}
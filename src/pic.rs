@@ -0,0 +1,84 @@
+// Legacy 8259 PIC pair, remapped so IRQ0..15 land on vectors 32..47.
+
+use crate::port::{inb, io_wait, outb};
+
+const PIC1_CMD: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_CMD: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+
+const ICW1_INIT: u8 = 0x10;
+const ICW1_ICW4: u8 = 0x01;
+const ICW4_8086: u8 = 0x01;
+const OCW3_READ_ISR: u8 = 0x0B;
+const EOI: u8 = 0x20;
+
+pub const IRQ_BASE: u8 = 32;
+const CASCADE_IRQ: u8 = 2;
+
+pub fn init() {
+    unsafe {
+        outb(PIC1_CMD, ICW1_INIT | ICW1_ICW4);
+        io_wait();
+        outb(PIC2_CMD, ICW1_INIT | ICW1_ICW4);
+        io_wait();
+        outb(PIC1_DATA, IRQ_BASE); // master vector offset
+        io_wait();
+        outb(PIC2_DATA, IRQ_BASE + 8); // slave vector offset
+        io_wait();
+        outb(PIC1_DATA, 1 << CASCADE_IRQ); // slave on IRQ2
+        io_wait();
+        outb(PIC2_DATA, CASCADE_IRQ); // slave cascade identity
+        io_wait();
+        outb(PIC1_DATA, ICW4_8086);
+        io_wait();
+        outb(PIC2_DATA, ICW4_8086);
+        io_wait();
+
+        // everything masked except the cascade; drivers unmask what they use
+        outb(PIC1_DATA, !(1 << CASCADE_IRQ));
+        outb(PIC2_DATA, 0xFF);
+    }
+}
+
+pub fn unmask(irq: u8) {
+    let (port, bit) = if irq < 8 {
+        (PIC1_DATA, irq)
+    } else {
+        (PIC2_DATA, irq - 8)
+    };
+    unsafe {
+        let mask = inb(port) & !(1 << bit);
+        outb(port, mask);
+    }
+}
+
+fn isr() -> u16 {
+    unsafe {
+        outb(PIC1_CMD, OCW3_READ_ISR);
+        outb(PIC2_CMD, OCW3_READ_ISR);
+        ((inb(PIC2_CMD) as u16) << 8) | inb(PIC1_CMD) as u16
+    }
+}
+
+/// IRQ7/IRQ15 fire spuriously when a line drops before the CPU acks it; the
+/// ISR bit is clear in that case and no EOI must be sent for it.
+pub fn is_spurious(irq: u8) -> bool {
+    (irq == 7 || irq == 15) && isr() & (1 << irq) == 0
+}
+
+pub fn eoi(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            outb(PIC2_CMD, EOI);
+        }
+        outb(PIC1_CMD, EOI);
+    }
+}
+
+/// EOI for a spurious IRQ15: the master still saw the cascade line.
+pub fn spurious_eoi(irq: u8) {
+    if irq == 15 {
+        unsafe { outb(PIC1_CMD, EOI) };
+    }
+}
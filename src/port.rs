@@ -0,0 +1,21 @@
+#[inline(always)]
+pub unsafe fn outb(port: u16, val: u8) {
+    unsafe {
+        core::arch::asm!("out dx, al", in("dx") port, in("al") val, options(nomem, nostack, preserves_flags));
+    }
+}
+
+#[inline(always)]
+pub unsafe fn inb(port: u16) -> u8 {
+    let val: u8;
+    unsafe {
+        core::arch::asm!("in al, dx", in("dx") port, out("al") val, options(nomem, nostack, preserves_flags));
+    }
+    val
+}
+
+/// Small delay by writing to an unused port, for old hardware like the PIC.
+#[inline(always)]
+pub unsafe fn io_wait() {
+    unsafe { outb(0x80, 0) };
+}
@@ -0,0 +1,625 @@
+// Processes: an address space, the regions mapped in it (VMAs), open files
+// and the threads running in it.
+//
+// A VMA is a promise, not a mapping: its pages get frames when first touched
+// (see page_fault.rs), zeroed or filled from the file behind the mapping. The
+// stack VMA grows down on demand, up to MAX_STACK, and the heap grows up from
+// the end of the image with `brk`. `fork` shares the parent's frames
+// copy-on-write instead of copying them, except in MAP_SHARED regions, which
+// stay shared; those are populated up front so there is something to share.
+//
+// A process goes away with its last thread. The teardown runs from the
+// scheduler after that thread has been switched out, so its page tables are
+// no longer loaded, and returns every page table and user frame to the
+// allocator; the thread stacks go back with the threads.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::elf::{self, ElfError};
+use crate::frame_alloc::PAGE_SIZE;
+use crate::paging::{AddressSpace, MapError, SHARED, USER, USER_BASE, USER_END, WRITABLE};
+use crate::sync::irq_spinlock::IrqSpinLock;
+use crate::usermode::UserRegs;
+use crate::{info, print, serial, thread};
+
+pub const MAX_PROCESSES: usize = 32;
+pub const MAX_FDS: usize = 16;
+/// Regions per process; mapping more fails with ENOMEM.
+pub const MAX_VMAS: usize = 64;
+
+/// Anonymous mappings without an address hint are placed from here up.
+const MMAP_BASE: u64 = 0x7000_0000_0000;
+/// How far the stack may grow; mmap leaves this much room below its top.
+pub const MAX_STACK: u64 = 8 << 20;
+const STACK_TOP: u64 = USER_END;
+
+pub const PROT_READ: u32 = 0x1;
+pub const PROT_WRITE: u32 = 0x2;
+pub const PROT_EXEC: u32 = 0x4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pid(u64);
+
+impl Pid {
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub const fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    /// Loaded from the program image.
+    Image,
+    Stack,
+    /// Grown and shrunk with `brk`.
+    Heap,
+    Anonymous,
+    /// `data[offset..]` from the region start on, zeros past its end.
+    File {
+        data: &'static [u8],
+        offset: u64,
+    },
+}
+
+/// A mapped region, `start..end`, page aligned.
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    /// PROT_* bits.
+    pub prot: u32,
+    /// MAP_SHARED: writes are seen by every process mapping it.
+    pub shared: bool,
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    const EMPTY: Self = Self {
+        start: 0,
+        end: 0,
+        prot: 0,
+        shared: false,
+        kind: VmaKind::Anonymous,
+    };
+
+    pub fn contains(&self, addr: u64) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    pub fn writable(&self) -> bool {
+        self.prot & PROT_WRITE != 0
+    }
+
+    /// Page table flags for the pages; none at all for PROT_NONE, so that
+    /// any user access faults. There is no NX yet: readable means
+    /// executable and the other way round.
+    pub fn page_flags(&self) -> u64 {
+        let mut flags = 0;
+        if self.prot != 0 {
+            flags |= USER;
+        }
+        if self.writable() {
+            flags |= WRITABLE;
+        }
+        if self.shared {
+            flags |= SHARED;
+        }
+        flags
+    }
+
+    /// Cuts the region in two at `addr`, which must lie inside it.
+    fn split(&mut self, addr: u64) -> Vma {
+        let mut upper = *self;
+        upper.start = addr;
+        if let VmaKind::File { data, offset } = self.kind {
+            // anything past the end of `data` reads as zeros alike
+            upper.kind = VmaKind::File {
+                data,
+                offset: offset.saturating_add(addr - self.start),
+            };
+        }
+        self.end = addr;
+        upper
+    }
+
+    /// Whether `next`, starting where this ends, could be the same region.
+    fn joins(&self, next: &Vma) -> bool {
+        self.end == next.start
+            && self.prot == next.prot
+            && self.shared == next.shared
+            && self.kind == next.kind
+            && matches!(self.kind, VmaKind::Heap | VmaKind::Anonymous)
+    }
+}
+
+/// The regions of a process, sorted by address and non-overlapping. A fixed
+/// array rather than a Vec: the kernel heap never frees, so lists that grow
+/// and get copied by fork would leak it a little at a time.
+#[derive(Clone, Copy)]
+struct Vmas {
+    slots: [Vma; MAX_VMAS],
+    len: usize,
+}
+
+impl Vmas {
+    const fn new() -> Self {
+        Self {
+            slots: [Vma::EMPTY; MAX_VMAS],
+            len: 0,
+        }
+    }
+
+    /// Fails if the list is full.
+    fn insert(&mut self, at: usize, vma: Vma) -> bool {
+        if self.len == MAX_VMAS {
+            return false;
+        }
+        self.slots.copy_within(at..self.len, at + 1);
+        self.slots[at] = vma;
+        self.len += 1;
+        true
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&Vma) -> bool) {
+        let mut kept = 0;
+        for at in 0..self.len {
+            if keep(&self.slots[at]) {
+                self.slots[kept] = self.slots[at];
+                kept += 1;
+            }
+        }
+        self.len = kept;
+    }
+}
+
+impl Deref for Vmas {
+    type Target = [Vma];
+    fn deref(&self) -> &[Vma] {
+        &self.slots[..self.len]
+    }
+}
+
+impl DerefMut for Vmas {
+    fn deref_mut(&mut self) -> &mut [Vma] {
+        &mut self.slots[..self.len]
+    }
+}
+
+/// What a file descriptor refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum File {
+    /// Input from a serial port, blocking until a byte arrives.
+    SerialIn(usize),
+    /// Output to all consoles.
+    Console,
+    /// A boot module, opened read-only. It can only be mapped for now.
+    Module(&'static [u8]),
+}
+
+impl File {
+    /// None if the file cannot be read.
+    pub fn read(&self, buf: &mut [u8]) -> Option<usize> {
+        match *self {
+            File::SerialIn(port) => Some(serial::read_wait(port, buf)),
+            File::Console | File::Module(_) => None,
+        }
+    }
+
+    /// None if the file cannot be written.
+    pub fn write(&self, buf: &[u8]) -> Option<usize> {
+        match *self {
+            File::SerialIn(_) | File::Module(_) => None,
+            File::Console => {
+                for chunk in buf.utf8_chunks() {
+                    print!("{}", chunk.valid());
+                    if !chunk.invalid().is_empty() {
+                        print!("{}", char::REPLACEMENT_CHARACTER);
+                    }
+                }
+                Some(buf.len())
+            }
+        }
+    }
+}
+
+pub struct Process {
+    pub pid: Pid,
+    pub name: &'static str,
+    pub space: AddressSpace,
+    vmas: Vmas,
+    pub files: [Option<File>; MAX_FDS],
+    /// Threads that have not been reaped yet.
+    threads: usize,
+    /// The heap runs from `brk_start`, just past the image, to `brk`.
+    brk_start: u64,
+    brk: u64,
+}
+
+impl Process {
+    pub fn vmas(&self) -> &[Vma] {
+        &self.vmas
+    }
+
+    pub fn find_vma(&self, addr: u64) -> Option<&Vma> {
+        self.vmas.iter().find(|v| v.contains(addr))
+    }
+
+    /// Records a region; fails if it overlaps one already there or there
+    /// are MAX_VMAS already. A region that continues its neighbour merges
+    /// with it.
+    pub fn add_vma(&mut self, vma: Vma) -> bool {
+        if !self.is_free(vma.start, vma.end) {
+            return false;
+        }
+        let at = self.vmas.partition_point(|v| v.start < vma.start);
+        if at > 0 && self.vmas[at - 1].joins(&vma) {
+            self.vmas[at - 1].end = vma.end;
+            true
+        } else {
+            self.vmas.insert(at, vma)
+        }
+    }
+
+    /// Whether no region overlaps `start..end`.
+    pub fn is_free(&self, start: u64, end: u64) -> bool {
+        !self.vmas.iter().any(|v| v.start < end && start < v.end)
+    }
+
+    /// Whether regions cover all of `start..end`, without gaps.
+    pub fn is_mapped(&self, start: u64, end: u64) -> bool {
+        let mut at = start;
+        for v in self.vmas.iter().filter(|v| v.end > start && v.start < end) {
+            if v.start > at {
+                return false;
+            }
+            at = v.end;
+        }
+        at >= end
+    }
+
+    /// Makes `addr` a region boundary, splitting the region around it.
+    /// Fails if that would take more than MAX_VMAS regions.
+    fn split_at(&mut self, addr: u64) -> bool {
+        let Some(at) = self
+            .vmas
+            .iter()
+            .position(|v| v.start < addr && addr < v.end)
+        else {
+            return true;
+        };
+        if self.vmas.len() == MAX_VMAS {
+            return false;
+        }
+        let upper = self.vmas[at].split(addr);
+        self.vmas.insert(at + 1, upper)
+    }
+
+    /// Removes whatever is mapped in `start..end` (page aligned), region
+    /// bookkeeping and pages alike. Fails, changing nothing that matters, if
+    /// cutting a region in two would take more than MAX_VMAS.
+    pub fn unmap(&mut self, start: u64, end: u64) -> bool {
+        if !self.split_at(start) || !self.split_at(end) {
+            return false;
+        }
+        self.vmas.retain(|v| v.end <= start || v.start >= end);
+        self.space.unmap_range(start, end);
+        true
+    }
+
+    /// Changes the protection of `start..end` (page aligned), which must be
+    /// mapped all over, pages included. Fails like `unmap`.
+    pub fn protect(&mut self, start: u64, end: u64, prot: u32) -> bool {
+        if !self.split_at(start) || !self.split_at(end) {
+            return false;
+        }
+        for v in self
+            .vmas
+            .iter_mut()
+            .filter(|v| v.start >= start && v.end <= end)
+        {
+            v.prot = prot;
+            self.space.protect_range(v.start, v.end, v.page_flags());
+        }
+        true
+    }
+
+    /// Maps every page of `vma` now rather than on first touch.
+    pub fn populate(&mut self, vma: &Vma) -> Result<(), MapError> {
+        for page in (vma.start..vma.end).step_by(PAGE_SIZE as usize) {
+            self.space.ensure_page(page, vma.page_flags())?;
+        }
+        Ok(())
+    }
+
+    /// Moves the program break to `addr` and returns where it ends up: at
+    /// `addr`, or where it was if the heap cannot be moved there.
+    pub fn set_brk(&mut self, addr: u64) -> u64 {
+        if addr < self.brk_start || addr > STACK_TOP - MAX_STACK {
+            return self.brk;
+        }
+        let old_end = self.brk.next_multiple_of(PAGE_SIZE);
+        let new_end = addr.next_multiple_of(PAGE_SIZE);
+        if new_end > old_end {
+            let heap = Vma {
+                start: old_end,
+                end: new_end,
+                prot: PROT_READ | PROT_WRITE,
+                shared: false,
+                kind: VmaKind::Heap,
+            };
+            if !self.add_vma(heap) {
+                return self.brk;
+            }
+        } else if new_end < old_end && !self.unmap(new_end, old_end) {
+            return self.brk;
+        }
+        self.brk = addr;
+        addr
+    }
+
+    /// Lowest gap of `len` bytes at or above MMAP_BASE.
+    pub fn find_free(&self, len: u64) -> Option<u64> {
+        let mut start = MMAP_BASE;
+        for v in self.vmas.iter().filter(|v| v.end > MMAP_BASE) {
+            if start.checked_add(len)? <= v.start {
+                break;
+            }
+            start = start.max(v.end);
+        }
+        (start.checked_add(len)? <= STACK_TOP - MAX_STACK).then_some(start)
+    }
+
+    /// Makes a user access to `addr` that faulted work, if the process may
+    /// make it: maps a zeroed frame, or the file data behind the page, or
+    /// grows the stack to cover it.
+    pub fn resolve_fault(&mut self, addr: u64, write: bool) -> bool {
+        let page = addr & !(PAGE_SIZE - 1);
+        let Some(vma) = self
+            .find_vma(addr)
+            .copied()
+            .or_else(|| self.grow_stack(page))
+        else {
+            return false;
+        };
+        if vma.prot == 0 || (write && !vma.writable()) {
+            return false;
+        }
+        let fresh = self.space.translate(page).is_none();
+        if self.space.ensure_page(page, vma.page_flags()).is_err() {
+            return false;
+        }
+        if let (true, VmaKind::File { data, offset }) = (fresh, vma.kind) {
+            let from = offset
+                .checked_add(page - vma.start)
+                .map_or(data.len(), |o| o.min(data.len() as u64) as usize);
+            let to = (from + PAGE_SIZE as usize).min(data.len());
+            return self.space.write(page, &data[from..to]).is_ok();
+        }
+        true
+    }
+
+    /// Extends the stack down to `page` if it is within MAX_STACK of the top
+    /// and a page clear of the region below.
+    fn grow_stack(&mut self, page: u64) -> Option<Vma> {
+        if page < USER_BASE {
+            return None;
+        }
+        let at = self.vmas.partition_point(|v| v.end <= page);
+        let below = at.checked_sub(1).map_or(USER_BASE, |i| self.vmas[i].end);
+        let stack = self.vmas.get_mut(at)?;
+        if stack.kind != VmaKind::Stack || stack.end - page > MAX_STACK || page < below + PAGE_SIZE
+        {
+            return None;
+        }
+        stack.start = page;
+        Some(*stack)
+    }
+
+    /// Lowest free descriptor.
+    pub fn open(&mut self, file: File) -> Option<usize> {
+        let fd = self.files.iter().position(|f| f.is_none())?;
+        self.files[fd] = Some(file);
+        Some(fd)
+    }
+
+    pub fn close(&mut self, fd: usize) -> bool {
+        self.files.get_mut(fd).and_then(|f| f.take()).is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    Elf(ElfError),
+    OutOfMemory,
+    TableFull,
+}
+
+impl From<ElfError> for SpawnError {
+    fn from(e: ElfError) -> Self {
+        SpawnError::Elf(e)
+    }
+}
+
+static PROCESSES: IrqSpinLock<[Option<Process>; MAX_PROCESSES]> =
+    IrqSpinLock::new([const { None }; MAX_PROCESSES]);
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+/// Loads `image` into a new process and starts its first thread, with
+/// `argv[0] = name` followed by `args`.
+pub fn spawn(name: &'static str, image: &[u8], args: &[&str]) -> Result<Pid, SpawnError> {
+    let mut argv = Vec::with_capacity(args.len() + 1);
+    argv.push(name);
+    argv.extend_from_slice(args);
+    let program = elf::load(image, &argv, &[])?;
+
+    let pid = Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed));
+    let image_end = program
+        .vmas
+        .iter()
+        .filter(|v| v.kind == VmaKind::Image)
+        .map(|v| v.end)
+        .max()
+        .unwrap_or(USER_BASE);
+    let mut process = Process {
+        pid,
+        name,
+        space: program.space,
+        vmas: Vmas::new(),
+        files: [None; MAX_FDS],
+        threads: 1,
+        brk_start: image_end,
+        brk: image_end,
+    };
+    if !program.vmas.into_iter().all(|vma| process.add_vma(vma)) {
+        program.space.destroy();
+        return Err(SpawnError::OutOfMemory);
+    }
+    process.files[0] = Some(File::SerialIn(0));
+    process.files[1] = Some(File::Console);
+    process.files[2] = Some(File::Console);
+
+    {
+        let mut table = PROCESSES.lock();
+        let Some(slot) = table.iter_mut().find(|p| p.is_none()) else {
+            drop(table);
+            program.space.destroy();
+            return Err(SpawnError::TableFull);
+        };
+        *slot = Some(process);
+    }
+    let regs = UserRegs::new(program.entry, program.stack);
+    if let Err(e) = thread::spawn_user(name, pid, program.space, regs) {
+        abandon(pid);
+        return Err(e);
+    }
+    info!("process {} ({}) started", pid, name);
+    Ok(pid)
+}
+
+/// Duplicates the current process: same memory (copy-on-write), same open
+/// files, and one thread that resumes in user mode with `regs`. The caller
+/// sets up the child's return value in them.
+pub fn fork(regs: &UserRegs) -> Result<Pid, SpawnError> {
+    let parent = current().expect("fork outside a process");
+    let pid = Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed));
+    let (name, space) = {
+        let mut table = PROCESSES.lock();
+        let slot = table
+            .iter()
+            .position(|p| p.is_none())
+            .ok_or(SpawnError::TableFull)?;
+        let p = table
+            .iter()
+            .flatten()
+            .find(|p| p.pid == parent)
+            .expect("current process missing");
+        let space = p.space.fork().map_err(|_| SpawnError::OutOfMemory)?;
+        let child = Process {
+            pid,
+            name: p.name,
+            space,
+            vmas: p.vmas,
+            files: p.files,
+            threads: 1,
+            brk_start: p.brk_start,
+            brk: p.brk,
+        };
+        let name = child.name;
+        table[slot] = Some(child);
+        (name, space)
+    };
+    if let Err(e) = thread::spawn_user(name, pid, space, *regs) {
+        abandon(pid);
+        return Err(e);
+    }
+    Ok(pid)
+}
+
+/// Takes back a process whose first thread could not be started.
+fn abandon(pid: Pid) {
+    let process = PROCESSES
+        .lock()
+        .iter_mut()
+        .find(|p| p.as_ref().is_some_and(|p| p.pid == pid))
+        .and_then(Option::take);
+    if let Some(process) = process {
+        process.space.destroy();
+    }
+}
+
+/// Makes sure user mode could access `start..start + len` in the current
+/// process, populating pages on the way, so the kernel can touch it without
+/// faulting. For checking buffers passed in system calls; large ones go a
+/// chunk at a time, so only what is used gets populated.
+pub fn fault_in(start: u64, len: u64, write: bool) -> bool {
+    let Some(end) = start.checked_add(len) else {
+        return false;
+    };
+    if start < USER_BASE || end > USER_END {
+        return false;
+    }
+    let mut page = start & !(PAGE_SIZE - 1);
+    while page < end {
+        // the table lock is only held a page at a time
+        let ok = with_current(|p| {
+            p.space.user_accessible(page, 1, write) || p.resolve_fault(page, write)
+        });
+        if ok != Some(true) {
+            return false;
+        }
+        page += PAGE_SIZE;
+    }
+    true
+}
+
+/// The process of the running thread, if it has one.
+pub fn current() -> Option<Pid> {
+    thread::current_process()
+}
+
+/// Runs `f` on process `pid` with the process table locked: keep it short,
+/// and do not block.
+pub fn with<R>(pid: Pid, f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let mut table = PROCESSES.lock();
+    table.iter_mut().flatten().find(|p| p.pid == pid).map(f)
+}
+
+/// `with` for the process of the running thread.
+pub fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    with(current()?, f)
+}
+
+/// A thread of `pid` was reaped. Called by the scheduler; the last one takes
+/// the process with it.
+pub(crate) fn thread_reaped(pid: Pid) {
+    let process = {
+        let mut table = PROCESSES.lock();
+        let Some(slot) = table
+            .iter_mut()
+            .find(|p| p.as_ref().is_some_and(|p| p.pid == pid))
+        else {
+            return;
+        };
+        let p = slot.as_mut().unwrap();
+        p.threads -= 1;
+        if p.threads > 0 {
+            return;
+        }
+        slot.take().unwrap()
+    };
+    process.space.destroy();
+    info!("process {} ({}) exited", pid, process.name);
+}
@@ -0,0 +1,202 @@
+// PC Screen Font parsing (PSF1 and PSF2).
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE512: u8 = 0x01;
+const PSF1_MODEHASTAB: u8 = 0x02;
+const PSF1_MODESEQ: u8 = 0x04;
+const PSF1_SEPARATOR: u16 = 0xFFFF;
+const PSF1_STARTSEQ: u16 = 0xFFFE;
+
+const PSF2_MAGIC: u32 = 0x864a_b572;
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_SEPARATOR: u8 = 0xFF;
+const PSF2_STARTSEQ: u8 = 0xFE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsfError {
+    Truncated,
+    BadMagic,
+    BadHeader,
+}
+
+#[derive(Clone, Copy)]
+enum UnicodeTable<'a> {
+    None,
+    Psf1(&'a [u8]),
+    Psf2(&'a [u8]),
+}
+
+#[derive(Clone, Copy)]
+pub struct Font<'a> {
+    pub width: usize,
+    pub height: usize,
+    glyph_count: usize,
+    bytes_per_glyph: usize,
+    bytes_per_row: usize,
+    glyphs: &'a [u8],
+    unicode: UnicodeTable<'a>,
+}
+
+fn read_u32(data: &[u8], off: usize) -> Option<u32> {
+    let b = data.get(off..off + 4)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+impl<'a> Font<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, PsfError> {
+        if data.len() >= 4 && data[0..2] == PSF1_MAGIC {
+            return Self::parse_psf1(data);
+        }
+        if read_u32(data, 0) == Some(PSF2_MAGIC) {
+            return Self::parse_psf2(data);
+        }
+        Err(PsfError::BadMagic)
+    }
+
+    fn parse_psf1(data: &'a [u8]) -> Result<Self, PsfError> {
+        let mode = data[2];
+        let charsize = data[3] as usize;
+        if charsize == 0 {
+            return Err(PsfError::BadHeader);
+        }
+
+        let glyph_count = if mode & PSF1_MODE512 != 0 { 512 } else { 256 };
+        let glyphs_end = 4 + glyph_count * charsize;
+        let glyphs = data.get(4..glyphs_end).ok_or(PsfError::Truncated)?;
+
+        let unicode = if mode & (PSF1_MODEHASTAB | PSF1_MODESEQ) != 0 {
+            UnicodeTable::Psf1(&data[glyphs_end..])
+        } else {
+            UnicodeTable::None
+        };
+
+        Ok(Self {
+            width: 8,
+            height: charsize,
+            glyph_count,
+            bytes_per_glyph: charsize,
+            bytes_per_row: 1,
+            glyphs,
+            unicode,
+        })
+    }
+
+    fn parse_psf2(data: &'a [u8]) -> Result<Self, PsfError> {
+        let field = |i: usize| read_u32(data, 4 * i).ok_or(PsfError::Truncated);
+        let header_size = field(2)? as usize;
+        let flags = field(3)?;
+        let glyph_count = field(4)? as usize;
+        let bytes_per_glyph = field(5)? as usize;
+        let height = field(6)? as usize;
+        let width = field(7)? as usize;
+
+        let bytes_per_row = width.div_ceil(8);
+        if width == 0 || height == 0 || bytes_per_glyph < bytes_per_row * height {
+            return Err(PsfError::BadHeader);
+        }
+
+        let glyphs_end = glyph_count
+            .checked_mul(bytes_per_glyph)
+            .and_then(|n| n.checked_add(header_size))
+            .ok_or(PsfError::BadHeader)?;
+        let glyphs = data
+            .get(header_size..glyphs_end)
+            .ok_or(PsfError::Truncated)?;
+
+        let unicode = if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            UnicodeTable::Psf2(&data[glyphs_end..])
+        } else {
+            UnicodeTable::None
+        };
+
+        Ok(Self {
+            width,
+            height,
+            glyph_count,
+            bytes_per_glyph,
+            bytes_per_row,
+            glyphs,
+            unicode,
+        })
+    }
+
+    /// Maps a character to a glyph index. Without a unicode table the font is
+    /// assumed to be CP437-ordered, so ASCII maps to itself.
+    pub fn glyph_index(&self, c: char) -> Option<usize> {
+        match self.unicode {
+            UnicodeTable::None => {
+                let i = c as usize;
+                (c.is_ascii() && i < self.glyph_count).then_some(i)
+            }
+            UnicodeTable::Psf1(table) => self.lookup_psf1(table, c),
+            UnicodeTable::Psf2(table) => self.lookup_psf2(table, c),
+        }
+    }
+
+    fn lookup_psf1(&self, table: &[u8], c: char) -> Option<usize> {
+        let mut glyph = 0;
+        let mut in_seq = false;
+        for pair in table.chunks_exact(2) {
+            let v = u16::from_le_bytes([pair[0], pair[1]]);
+            match v {
+                PSF1_SEPARATOR => {
+                    glyph += 1;
+                    in_seq = false;
+                    if glyph >= self.glyph_count {
+                        break;
+                    }
+                }
+                PSF1_STARTSEQ => in_seq = true,
+                v if !in_seq && v as u32 == c as u32 => return Some(glyph),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn lookup_psf2(&self, table: &[u8], c: char) -> Option<usize> {
+        let mut buf = [0u8; 4];
+        let needle = c.encode_utf8(&mut buf).as_bytes();
+
+        let mut glyph = 0;
+        let mut in_seq = false;
+        let mut i = 0;
+        while i < table.len() && glyph < self.glyph_count {
+            match table[i] {
+                PSF2_SEPARATOR => {
+                    glyph += 1;
+                    in_seq = false;
+                    i += 1;
+                }
+                PSF2_STARTSEQ => {
+                    in_seq = true;
+                    i += 1;
+                }
+                b => {
+                    let len = match b {
+                        0x00..=0x7F => 1,
+                        0xC0..=0xDF => 2,
+                        0xE0..=0xEF => 3,
+                        _ => 4,
+                    };
+                    if !in_seq && table.get(i..i + len) == Some(needle) {
+                        return Some(glyph);
+                    }
+                    i += len;
+                }
+            }
+        }
+        None
+    }
+
+    /// Bitmap rows of glyph `index`, `bytes_per_row` bytes each, MSB first.
+    pub fn glyph(&self, index: usize) -> &'a [u8] {
+        let index = if index < self.glyph_count { index } else { 0 };
+        let start = index * self.bytes_per_glyph;
+        &self.glyphs[start..start + self.bytes_per_row * self.height]
+    }
+
+    pub fn bytes_per_row(&self) -> usize {
+        self.bytes_per_row
+    }
+}
@@ -1,60 +1,516 @@
+// 16550 UART driver for COM1-COM4.
+//
+// Output is queued in a per-port TX ring and drained by the THR-empty
+// interrupt; input is collected by the RX interrupt into the line discipline.
+// Until `init_irqs` runs (or for a port without a working IRQ) both directions
+// fall back to polling the LSR.
+//
+// Line settings come from `console=ttyS<n>,<options>` (see `config`); ports
+// without one use `SerialConfig::DEFAULT`.
+
+pub mod config;
+mod ldisc;
+mod ring;
+
 use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::console::{self, Console};
+use crate::port::{inb, outb};
+use crate::sync::irq_spinlock::IrqSpinLock;
+use crate::sync::waitqueue::WaitQueue;
+use crate::thread;
+use crate::{cmdline, cpu, info, irq, warn};
+
+use config::FlowControl;
+pub use config::SerialConfig;
+use ldisc::LineDiscipline;
+use ring::Ring;
+
+pub const PORT_COUNT: usize = 4;
+const COM_BASES: [u16; PORT_COUNT] = [0x3F8, 0x2F8, 0x3E8, 0x2E8];
+const COM_IRQS: [u8; PORT_COUNT] = [4, 3, 4, 3];
+const COM_NAMES: [&str; PORT_COUNT] = ["ttyS0", "ttyS1", "ttyS2", "ttyS3"];
+
+const COM1: u16 = COM_BASES[0];
+
+// register offsets
+const DATA: u16 = 0;
+const IER: u16 = 1;
+const IIR: u16 = 2; // FCR on write
+const LCR: u16 = 3;
+const MCR: u16 = 4;
+const LSR: u16 = 5;
+const MSR: u16 = 6;
+const SCRATCH: u16 = 7;
+
+const IER_RX: u8 = 0x01;
+const IER_THRE: u8 = 0x02;
+const IER_LINE: u8 = 0x04;
+const IER_MODEM: u8 = 0x08;
+
+const IIR_NONE: u8 = 0x01;
+const IIR_ID_MASK: u8 = 0x0E;
+const IIR_MODEM: u8 = 0x00;
+const IIR_THRE: u8 = 0x02;
+const IIR_RX: u8 = 0x04;
+const IIR_LINE: u8 = 0x06;
+const IIR_RX_TIMEOUT: u8 = 0x0C;
+
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THRE: u8 = 0x20;
+
+const LCR_DLAB: u8 = 0x80;
+
+const FCR_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+const FCR_CLEAR_TX: u8 = 0x04;
+const FCR_TRIGGER_14: u8 = 0xC0;
+
+const MSR_CTS: u8 = 0x10;
+
+const MCR_DTR: u8 = 0x01;
+const MCR_RTS: u8 = 0x02;
+const MCR_OUT1: u8 = 0x04;
+const MCR_OUT2: u8 = 0x08; // gates the IRQ line on PCs
+const MCR_LOOPBACK: u8 = 0x10;
+
+const FIFO_SIZE: usize = 16;
+const TX_SIZE: usize = 4096;
+const LOOPBACK_PROBE: u8 = 0xAE;
+const LOOPBACK_SPINS: usize = 10_000;
+const EMERGENCY_SPINS: usize = 100_000;
+
+struct Uart {
+    base: u16,
+    initialized: bool,
+    present: bool,
+    config: SerialConfig,
+    irq_driven: bool,
+    ier: u8,
+    tx: Ring<TX_SIZE>,
+    ldisc: LineDiscipline,
+}
+
+impl Uart {
+    const fn new(base: u16) -> Self {
+        Self {
+            base,
+            initialized: false,
+            present: false,
+            config: SerialConfig::DEFAULT,
+            irq_driven: false,
+            ier: 0,
+            tx: Ring::new(),
+            ldisc: LineDiscipline::new(),
+        }
+    }
+
+    fn read_reg(&self, reg: u16) -> u8 {
+        unsafe { inb(self.base + reg) }
+    }
+
+    fn write_reg(&self, reg: u16, val: u8) {
+        unsafe { outb(self.base + reg, val) }
+    }
+
+    /// Scratch register first (cheap, rules out an empty bus), then a byte
+    /// through loopback mode to make sure a UART is really there.
+    fn probe(&mut self) -> bool {
+        self.write_reg(SCRATCH, 0x5A);
+        if self.read_reg(SCRATCH) != 0x5A {
+            return false;
+        }
+
+        self.program();
+
+        self.write_reg(MCR, MCR_LOOPBACK | MCR_OUT1 | MCR_OUT2 | MCR_RTS);
+        self.write_reg(DATA, LOOPBACK_PROBE);
+        let mut ok = false;
+        for _ in 0..LOOPBACK_SPINS {
+            if self.read_reg(LSR) & LSR_DATA_READY != 0 {
+                ok = self.read_reg(DATA) == LOOPBACK_PROBE;
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        self.write_reg(MCR, MCR_DTR | MCR_RTS | MCR_OUT2);
+        ok
+    }
+
+    /// Applies `self.config` with interrupts off; the caller re-enables them.
+    fn program(&mut self) {
+        let divisor = self.config.divisor().unwrap_or(3);
+        self.ier = 0;
+        self.write_reg(IER, 0x00);
+        self.write_reg(LCR, LCR_DLAB);
+        self.write_reg(DATA, divisor as u8);
+        self.write_reg(IER, (divisor >> 8) as u8);
+        self.write_reg(LCR, self.config.lcr());
+        self.write_reg(
+            IIR,
+            FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | FCR_TRIGGER_14,
+        );
+        self.write_reg(MCR, MCR_DTR | MCR_RTS | MCR_OUT2);
+    }
+
+    /// Interrupts wanted in IRQ-driven mode, apart from THR-empty.
+    fn base_ier(&self) -> u8 {
+        match self.config.flow {
+            FlowControl::None => IER_RX | IER_LINE,
+            FlowControl::RtsCts => IER_RX | IER_LINE | IER_MODEM,
+        }
+    }
+
+    /// With RTS/CTS the peer holds CTS low while it cannot take more.
+    fn clear_to_send(&self) -> bool {
+        self.config.flow == FlowControl::None || self.read_reg(MSR) & MSR_CTS != 0
+    }
+
+    fn set_ier(&mut self, ier: u8) {
+        if ier != self.ier {
+            self.ier = ier;
+            self.write_reg(IER, ier);
+        }
+    }
+
+    /// Moves queued bytes into the FIFO if it is empty, and keeps the
+    /// THR-empty interrupt armed exactly while there is more to send.
+    /// Under flow control a low CTS parks the ring until the modem status
+    /// interrupt reports it high again.
+    fn start_tx(&mut self) {
+        if self.read_reg(LSR) & LSR_THRE != 0 && self.clear_to_send() {
+            for _ in 0..FIFO_SIZE {
+                match self.tx.pop() {
+                    Some(b) => self.write_reg(DATA, b),
+                    None => break,
+                }
+            }
+        }
+
+        let ier = if self.irq_driven && !self.tx.is_empty() && self.clear_to_send() {
+            self.ier | IER_THRE
+        } else {
+            self.ier & !IER_THRE
+        };
+        self.set_ier(ier);
+    }
 
-const COM1: u16 = 0x3F8;
+    fn drain_rx(&mut self) {
+        while self.read_reg(LSR) & LSR_DATA_READY != 0 {
+            let b = self.read_reg(DATA);
+            self.ldisc.receive(b, &mut self.tx);
+        }
+        // echo
+        self.start_tx();
+    }
 
-#[inline(always)]
-unsafe fn outb(port: u16, val: u8) {
-    unsafe {
-        core::arch::asm!("out dx, al", in("dx") port, in("al") val, options(nomem, nostack, preserves_flags));
+    fn service_irq(&mut self) {
+        loop {
+            let iir = self.read_reg(IIR);
+            if iir & IIR_NONE != 0 {
+                break;
+            }
+            match iir & IIR_ID_MASK {
+                IIR_RX | IIR_RX_TIMEOUT => self.drain_rx(),
+                IIR_THRE => self.start_tx(),
+                IIR_LINE => {
+                    self.read_reg(LSR);
+                }
+                IIR_MODEM => {
+                    self.read_reg(MSR);
+                    self.start_tx();
+                }
+                _ => break,
+            }
+        }
     }
 }
 
-#[inline(always)]
-unsafe fn inb(port: u16) -> u8 {
-    let val: u8;
-    unsafe {
-        core::arch::asm!("in al, dx", in("dx") port, out("al") val, options(nomem, nostack, preserves_flags));
+static PORTS: [IrqSpinLock<Uart>; PORT_COUNT] = [
+    IrqSpinLock::new(Uart::new(COM_BASES[0])),
+    IrqSpinLock::new(Uart::new(COM_BASES[1])),
+    IrqSpinLock::new(Uart::new(COM_BASES[2])),
+    IrqSpinLock::new(Uart::new(COM_BASES[3])),
+];
+
+/// Readers sleeping in `read_wait`, woken on every interrupt from the port.
+static RX_WAIT: [WaitQueue; PORT_COUNT] = [const { WaitQueue::new() }; PORT_COUNT];
+
+fn with_port<R>(port: usize, f: impl FnOnce(&mut Uart) -> R) -> R {
+    f(&mut PORTS[port].lock())
+}
+
+/// `console=ttyS<n>,<options>` for `port`, if given and valid.
+fn cmdline_config(port: usize) -> Option<SerialConfig> {
+    let mut config = None;
+    for arg in cmdline::values("console") {
+        let Some((name, opts)) = arg.split_once(',') else {
+            continue;
+        };
+        if name != COM_NAMES[port] {
+            continue;
+        }
+        match SerialConfig::parse(opts) {
+            Some(c) => config = Some(c),
+            None => warn!("{}: bad options {:?}, using defaults", name, opts),
+        }
     }
-    val
+    config
 }
 
+/// Probes COM1-COM4, programs the ones that answer in polled mode and
+/// registers them as consoles. Ports already set up are left alone, so
+/// calling it again is harmless.
 pub fn init() {
-    unsafe {
-        outb(COM1 + 1, 0x00); // disable interrupts
-        outb(COM1 + 3, 0x80); // enable DLAB
-        outb(COM1 + 0, 0x03); // divisor low  (38400 baud if base is 115200)
-        outb(COM1 + 1, 0x00); // divisor high
-        outb(COM1 + 3, 0x03); // 8 bits, no parity, one stop bit
-        outb(COM1 + 2, 0xC7); // enable FIFO, clear, 14-byte threshold
-        outb(COM1 + 4, 0x0B); // IRQs enabled, RTS/DSR set (doesn't hurt in QEMU)
+    for port in 0..PORT_COUNT {
+        let config = cmdline_config(port);
+        let probed = with_port(port, |u| {
+            if u.initialized {
+                return None;
+            }
+            u.config = config.unwrap_or(SerialConfig::DEFAULT);
+            u.present = u.probe();
+            u.initialized = true;
+            Some((u.present, u.config))
+        });
+        if let Some((true, c)) = probed {
+            info!("{} at {:#x}: {}", COM_NAMES[port], COM_BASES[port], c);
+            console::register(&CONSOLES[port]);
+        }
     }
 }
 
-pub fn write_byte(b: u8) {
-    unsafe {
-        // wait until THR empty
-        while (inb(COM1 + 5) & 0x20) == 0 {}
-        outb(COM1, b);
+/// Switches present ports to interrupt-driven I/O. Needs the PIC set up.
+pub fn init_irqs() {
+    for (port, &line) in COM_IRQS.iter().enumerate() {
+        let present = with_port(port, |u| {
+            if u.present {
+                u.irq_driven = true;
+                let ier = u.base_ier();
+                u.set_ier(ier);
+                u.start_tx();
+            }
+            u.present
+        });
+        if present {
+            let handler: irq::IrqHandler = if line == 4 { on_irq4 } else { on_irq3 };
+            irq::register(line, handler);
+        }
+    }
+}
+
+fn on_irq(irq: u8) {
+    for port in (0..PORT_COUNT).filter(|&p| COM_IRQS[p] == irq) {
+        let mut u = PORTS[port].lock();
+        if u.present && u.irq_driven {
+            u.service_irq();
+            drop(u);
+            RX_WAIT[port].wake_all();
+        }
+    }
+}
+
+fn on_irq4() {
+    on_irq(4);
+}
+
+fn on_irq3() {
+    on_irq(3);
+}
+
+#[allow(dead_code)]
+pub fn is_present(port: usize) -> bool {
+    with_port(port, |u| u.present)
+}
+
+#[allow(dead_code)]
+pub fn config(port: usize) -> SerialConfig {
+    with_port(port, |u| u.config)
+}
+
+/// Reprograms `port` with new line settings. Anything still in the TX ring
+/// goes out at the new rate. Returns false if the baud rate is unusable.
+#[allow(dead_code)]
+pub fn set_config(port: usize, config: SerialConfig) -> bool {
+    if config.divisor().is_none() {
+        return false;
     }
+    with_port(port, |u| {
+        u.config = config;
+        u.program();
+        if u.irq_driven {
+            let ier = u.base_ier();
+            u.set_ier(ier);
+        }
+        u.start_tx();
+    });
+    true
+}
+
+/// Queues as much of `bytes` as fits without waiting; returns the count.
+pub fn write(port: usize, bytes: &[u8]) -> usize {
+    with_port(port, |u| {
+        let n = u.tx.push_slice(bytes);
+        u.start_tx();
+        n
+    })
 }
 
+/// Returns whatever input the line discipline has ready, without waiting.
+pub fn read(port: usize, buf: &mut [u8]) -> usize {
+    with_port(port, |u| {
+        if !u.irq_driven {
+            u.drain_rx();
+        }
+        u.ldisc.read(buf)
+    })
+}
+
+/// Like `read`, but sleeps until at least one byte is ready.
+pub fn read_wait(port: usize, buf: &mut [u8]) -> usize {
+    let mut n = 0;
+    if with_port(port, |u| u.irq_driven) {
+        RX_WAIT[port].wait_until(|| {
+            n = read(port, buf);
+            n > 0
+        });
+    } else {
+        loop {
+            n = read(port, buf);
+            if n > 0 {
+                break;
+            }
+            thread::yield_now();
+        }
+    }
+    n
+}
+
+/// Echo and line buffering for input on `port`.
+#[allow(dead_code)]
+pub fn set_line_mode(port: usize, echo: bool, canonical: bool) {
+    with_port(port, |u| {
+        u.ldisc.echo = echo;
+        u.ldisc.canonical = canonical;
+    });
+}
+
+/// Writes all of `bytes`, polling the UART when the TX ring is full or
+/// nothing else will drain it.
+fn write_all(port: usize, mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+        let n = write(port, bytes);
+        bytes = &bytes[n..];
+        if n == 0 {
+            core::hint::spin_loop();
+            with_port(port, |u| u.start_tx());
+        }
+    }
+
+    with_port(port, |u| {
+        while !u.irq_driven && !u.tx.is_empty() {
+            core::hint::spin_loop();
+            u.start_tx();
+        }
+    });
+}
+
+/// Set once COM1 failed to drain a byte in time; later emergency output is
+/// dropped instead of timing out on every byte.
+static EMERGENCY_STUCK: AtomicBool = AtomicBool::new(false);
+
+/// Polled COM1 output that takes no lock and never reprograms the port, so it
+/// is safe from panic and exception context whatever state the driver is in.
+fn emergency_byte(b: u8) {
+    if EMERGENCY_STUCK.load(Ordering::Relaxed) {
+        return;
+    }
+    for _ in 0..EMERGENCY_SPINS {
+        if unsafe { inb(COM1 + LSR) } & LSR_THRE != 0 {
+            unsafe { outb(COM1 + DATA, b) };
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    EMERGENCY_STUCK.store(true, Ordering::Relaxed);
+}
+
+fn emergency_write(s: &str) {
+    for &b in s.as_bytes() {
+        if b == b'\n' {
+            emergency_byte(b'\r');
+        }
+        emergency_byte(b);
+    }
+}
+
+/// Unbuffered COM1 output for exception and panic reports, see
+/// `emergency_byte`.
 pub struct Serial;
 
 impl fmt::Write for Serial {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for &b in s.as_bytes() {
-            if b == b'\n' {
-                write_byte(b'\r');
+        emergency_write(s);
+        Ok(())
+    }
+}
+
+pub struct SerialConsole {
+    port: usize,
+}
+
+pub static CONSOLES: [SerialConsole; PORT_COUNT] = [
+    SerialConsole { port: 0 },
+    SerialConsole { port: 1 },
+    SerialConsole { port: 2 },
+    SerialConsole { port: 3 },
+];
+
+impl Console for SerialConsole {
+    fn name(&self) -> &'static str {
+        COM_NAMES[self.port]
+    }
+
+    fn write_str(&self, s: &str) {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                write_all(self.port, b"\r\n");
             }
-            write_byte(b);
+            write_all(self.port, line.as_bytes());
         }
-        Ok(())
     }
 }
 
+/// Consoles for the ports `init` found.
+/// CPU currently writing through `_print`.
+static EMERGENCY_OWNER: AtomicUsize = AtomicUsize::new(cpu::NO_CPU);
+
+/// Emergency output, one whole message at a time. Re-entry on the same CPU
+/// (a fault in the middle of a report) writes straight through instead of
+/// waiting on itself, and a holder that never lets go is only waited for a
+/// bounded time.
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    let _ = Serial.write_fmt(args);
+    irq::without_interrupts(|| {
+        let cpu = cpu::id();
+        let mut owned = false;
+        if EMERGENCY_OWNER.load(Ordering::Relaxed) != cpu {
+            for _ in 0..EMERGENCY_SPINS {
+                owned = EMERGENCY_OWNER
+                    .compare_exchange(cpu::NO_CPU, cpu, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok();
+                if owned {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+        let _ = Serial.write_fmt(args);
+        if owned {
+            EMERGENCY_OWNER.store(cpu::NO_CPU, Ordering::Release);
+        }
+    });
 }
 
 #[macro_export]
@@ -0,0 +1,120 @@
+// Line settings, in the `console=ttyS<n>,<options>` format Linux uses:
+//
+//   <baud>[<parity>[<bits>[<stop>]]][r]     e.g. 115200n8, 9600e72, 57600n8r
+//
+// parity is n/o/e, bits 5-8, stop 1 or 2; a trailing `r` enables RTS/CTS.
+
+use core::fmt;
+
+/// The UART input clock divided by 16: divisor 1 gives this baud rate.
+const BASE_BAUD: u32 = 115_200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowControl {
+    None,
+    RtsCts,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: u8,
+    pub flow: FlowControl,
+}
+
+impl SerialConfig {
+    pub const DEFAULT: Self = Self {
+        baud: 38_400,
+        data_bits: 8,
+        parity: Parity::None,
+        stop_bits: 1,
+        flow: FlowControl::None,
+    };
+
+    /// Parses the options part of `console=ttyS0,<options>`. Fields that are
+    /// left out keep their default; anything malformed rejects the whole string.
+    pub fn parse(opts: &str) -> Option<Self> {
+        let mut cfg = Self::DEFAULT;
+        let digits = opts
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(opts.len());
+        cfg.baud = opts[..digits].parse().ok()?;
+
+        let mut rest = opts[digits..].bytes().peekable();
+        if let Some(&p) = rest.peek() {
+            let parity = match p {
+                b'n' => Some(Parity::None),
+                b'o' => Some(Parity::Odd),
+                b'e' => Some(Parity::Even),
+                _ => None,
+            };
+            if let Some(parity) = parity {
+                cfg.parity = parity;
+                rest.next();
+            }
+        }
+        if let Some(b @ b'5'..=b'8') = rest.peek().copied() {
+            cfg.data_bits = b - b'0';
+            rest.next();
+        }
+        if let Some(b @ b'1'..=b'2') = rest.peek().copied() {
+            cfg.stop_bits = b - b'0';
+            rest.next();
+        }
+        if rest.peek() == Some(&b'r') {
+            cfg.flow = FlowControl::RtsCts;
+            rest.next();
+        }
+
+        (rest.next().is_none() && cfg.divisor().is_some()).then_some(cfg)
+    }
+
+    /// Divisor latch value, or `None` if the rate cannot be generated exactly.
+    pub fn divisor(&self) -> Option<u16> {
+        if self.baud == 0 || !BASE_BAUD.is_multiple_of(self.baud) {
+            return None;
+        }
+        u16::try_from(BASE_BAUD / self.baud).ok()
+    }
+
+    /// Line control register value (DLAB clear).
+    pub fn lcr(&self) -> u8 {
+        let bits = self.data_bits.clamp(5, 8) - 5;
+        let stop = if self.stop_bits == 2 { 0x04 } else { 0 };
+        let parity = match self.parity {
+            Parity::None => 0x00,
+            Parity::Odd => 0x08,
+            Parity::Even => 0x18,
+        };
+        bits | stop | parity
+    }
+}
+
+/// `115200 8N1`, `9600 7E2 rtscts`.
+impl fmt::Display for SerialConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+        };
+        write!(
+            f,
+            "{} {}{}{}",
+            self.baud, self.data_bits, parity, self.stop_bits
+        )?;
+        if self.flow == FlowControl::RtsCts {
+            write!(f, " rtscts")?;
+        }
+        Ok(())
+    }
+}
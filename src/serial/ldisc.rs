@@ -0,0 +1,104 @@
+// Line discipline between the UART and readers: echo, backspace handling and
+// (in canonical mode) handing out input a line at a time.
+
+use super::ring::Ring;
+
+const LINE_MAX: usize = 256;
+const INPUT_SIZE: usize = 1024;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const KILL_LINE: u8 = 0x15; // ^U
+const BELL: u8 = 0x07;
+
+pub struct LineDiscipline {
+    pub echo: bool,
+    pub canonical: bool,
+    line: [u8; LINE_MAX],
+    line_len: usize,
+    /// Input ready for `read`: whole lines in canonical mode, raw bytes otherwise.
+    input: Ring<INPUT_SIZE>,
+}
+
+impl LineDiscipline {
+    pub const fn new() -> Self {
+        Self {
+            echo: true,
+            canonical: true,
+            line: [0; LINE_MAX],
+            line_len: 0,
+            input: Ring::new(),
+        }
+    }
+
+    /// Processes one received byte; echo goes to `out`.
+    pub fn receive<const N: usize>(&mut self, b: u8, out: &mut Ring<N>) {
+        if !self.canonical {
+            self.input.push(b);
+            if self.echo {
+                out.push(b);
+            }
+            return;
+        }
+
+        match b {
+            b'\r' | b'\n' => {
+                // a line goes in whole or not at all: while readers have
+                // not made room for it, it stays in the edit buffer and
+                // Enter only rings the bell
+                if self.input.space() < self.line_len + 1 {
+                    if self.echo {
+                        out.push(BELL);
+                    }
+                    return;
+                }
+                self.input.push_slice(&self.line[..self.line_len]);
+                self.input.push(b'\n');
+                self.line_len = 0;
+                if self.echo {
+                    out.push_slice(b"\r\n");
+                }
+            }
+            BACKSPACE | DELETE => {
+                if self.line_len > 0 {
+                    self.line_len -= 1;
+                    if self.echo {
+                        out.push_slice(b"\x08 \x08");
+                    }
+                }
+            }
+            KILL_LINE => {
+                while self.line_len > 0 {
+                    self.line_len -= 1;
+                    if self.echo {
+                        out.push_slice(b"\x08 \x08");
+                    }
+                }
+            }
+            b => {
+                // keep room for the newline
+                if self.line_len < LINE_MAX - 1 {
+                    self.line[self.line_len] = b;
+                    self.line_len += 1;
+                    if self.echo {
+                        out.push(b);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut n = 0;
+        while n < buf.len() {
+            match self.input.pop() {
+                Some(b) => {
+                    buf[n] = b;
+                    n += 1;
+                }
+                None => break,
+            }
+        }
+        n
+    }
+}
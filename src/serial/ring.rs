@@ -0,0 +1,49 @@
+/// Fixed-size byte FIFO.
+pub struct Ring<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> Ring<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes that can still be pushed.
+    pub fn space(&self) -> usize {
+        N - self.len
+    }
+
+    pub fn push(&mut self, b: u8) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = b;
+        self.len += 1;
+        true
+    }
+
+    /// Pushes as much of `bytes` as fits; returns how many were taken.
+    pub fn push_slice(&mut self, bytes: &[u8]) -> usize {
+        bytes.iter().take_while(|&&b| self.push(b)).count()
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let b = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(b)
+    }
+}
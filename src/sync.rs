@@ -1,2 +1,15 @@
+pub mod condvar;
+pub mod irq_spinlock;
 pub mod lazy;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+pub mod mcs;
+pub mod mutex;
+pub mod once;
+pub mod rwlock;
+pub mod semaphore;
+pub mod seqlock;
 pub mod spinlock;
+pub mod stats;
+pub mod ticket;
+pub mod waitqueue;
@@ -0,0 +1,53 @@
+use crate::sync::mutex::MutexGuard;
+use crate::sync::waitqueue::WaitQueue;
+use crate::thread;
+
+/// Condition variable for use with `Mutex`. Wakeups may be spurious; wait
+/// in a loop or use `wait_while`.
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    #[allow(dead_code)]
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks `guard`'s mutex, sleeps until notified and locks it again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        // queued before unlocking, so a notify right after the unlock
+        // cannot be missed
+        self.waiters.prepare_to_wait();
+        drop(guard);
+        thread::block();
+        self.waiters.finish_wait();
+        mutex.lock()
+    }
+
+    /// Waits for as long as `cond` holds on the protected data.
+    #[allow(dead_code)]
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut cond: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while cond(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    #[allow(dead_code)]
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    #[allow(dead_code)]
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
@@ -0,0 +1,98 @@
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::spinlock::{SpinLock, SpinLockGuard};
+use crate::{cpu, irq};
+
+/// A `SpinLock` for data shared with interrupt handlers: interrupts are
+/// disabled for as long as the guard lives, so a handler can never spin on a
+/// lock held by the code it interrupted on the same CPU. The previous IF
+/// state is restored on drop, so guards nest, but they must be dropped
+/// innermost first: dropping an outer guard early would turn interrupts back
+/// on while the inner lock is still held. Debug builds check the order.
+pub struct IrqSpinLock<T> {
+    inner: SpinLock<T>,
+}
+
+pub struct IrqSpinLockGuard<'a, T> {
+    guard: ManuallyDrop<SpinLockGuard<'a, T>>,
+    was_enabled: bool,
+    /// Guards held on this CPU, this one included.
+    depth: usize,
+}
+
+/// `IrqSpinLockGuard`s alive per CPU.
+static DEPTH: [AtomicUsize; cpu::MAX_CPUS] = [const { AtomicUsize::new(0) }; cpu::MAX_CPUS];
+
+/// Counts a new guard; interrupts are already off, so the CPU cannot change.
+fn push() -> usize {
+    DEPTH[cpu::id()].fetch_add(1, Ordering::Relaxed) + 1
+}
+
+impl<T> IrqSpinLock<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: SpinLock::new(value),
+        }
+    }
+
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let was_enabled = irq::save_and_disable();
+        let guard = ManuallyDrop::new(self.inner.lock());
+        IrqSpinLockGuard {
+            guard,
+            was_enabled,
+            depth: push(),
+        }
+    }
+
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let was_enabled = irq::save_and_disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinLockGuard {
+                guard: ManuallyDrop::new(guard),
+                was_enabled,
+                depth: push(),
+            }),
+            None => {
+                irq::restore(was_enabled);
+                None
+            }
+        }
+    }
+
+    /// Racy by nature; for diagnostics and assertions only.
+    #[allow(dead_code)]
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+impl<T> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        let depth = DEPTH[cpu::id()].fetch_sub(1, Ordering::Relaxed);
+        debug_assert_eq!(depth, self.depth, "IrqSpinLock guards dropped out of order");
+        // release before interrupts come back on
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        irq::restore(self.was_enabled);
+    }
+}
@@ -0,0 +1,317 @@
+// Lock dependency validator, built with `--features lockdep`.
+//
+// Every `SpinLock` belongs to a class: the place it was constructed. Each
+// thread keeps a stack of the locks it holds (interrupt handlers count as the
+// thread they interrupted), and taking B while holding A records the edge
+// A -> B. A new edge that closes a cycle, or taking a lock that is already
+// held, is an ordering that can deadlock. It is reported on the emergency
+// serial path with the call sites involved, before the lock is spun on,
+// whether or not this particular run would have hung.
+//
+// Locks built at the same site (in a loop, or one per object) share a class;
+// nesting two of them is not tracked, as nothing tells their order apart.
+
+use core::cell::UnsafeCell;
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use crate::thread::{self, MAX_THREADS};
+use crate::{irq, serial_println};
+
+const MAX_CLASSES: usize = 64;
+const MAX_HELD: usize = 32;
+const NO_CLASS: usize = usize::MAX;
+const FULL: usize = usize::MAX - 1;
+
+type Site = &'static Location<'static>;
+
+/// Per-lock handle; the class id is claimed on first acquisition.
+pub struct Class {
+    site: Site,
+    id: AtomicUsize,
+}
+
+impl Class {
+    #[track_caller]
+    pub const fn new() -> Self {
+        Self {
+            site: Location::caller(),
+            id: AtomicUsize::new(NO_CLASS),
+        }
+    }
+
+    fn id(&self) -> Option<usize> {
+        let mut id = self.id.load(Ordering::Relaxed);
+        if id == NO_CLASS {
+            id = claim(self.site);
+            self.id.store(id, Ordering::Relaxed);
+        }
+        (id < MAX_CLASSES).then_some(id)
+    }
+}
+
+static CLASSES: [AtomicPtr<Location<'static>>; MAX_CLASSES] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CLASSES];
+
+fn claim(site: Site) -> usize {
+    let want = site as *const _ as *mut Location<'static>;
+    for (i, class) in CLASSES.iter().enumerate() {
+        match class.compare_exchange(ptr::null_mut(), want, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return i,
+            Err(cur) if cur == want => return i,
+            Err(_) => {}
+        }
+    }
+    warn_once(
+        &TABLE_FULL,
+        format_args!("class table full, {} not tracked", site),
+    );
+    FULL
+}
+
+fn class_site(id: usize) -> Site {
+    unsafe { &*CLASSES[id].load(Ordering::Acquire) }
+}
+
+#[derive(Clone, Copy)]
+struct Held {
+    class: usize,
+    /// The lock itself, by the address of its `Class`.
+    lock: *const Class,
+    site: Site,
+}
+
+struct HeldStack {
+    entries: [Option<Held>; MAX_HELD],
+    depth: usize,
+}
+
+struct PerThread(UnsafeCell<HeldStack>);
+
+// Safety: only the running thread touches its entry, with interrupts off.
+unsafe impl Sync for PerThread {}
+
+static HELD: [PerThread; MAX_THREADS] = [const {
+    PerThread(UnsafeCell::new(HeldStack {
+        entries: [None; MAX_HELD],
+        depth: 0,
+    }))
+}; MAX_THREADS];
+
+fn with_held<R>(f: impl FnOnce(&mut HeldStack) -> R) -> R {
+    irq::without_interrupts(|| f(unsafe { &mut *HELD[thread::current_slot()].0.get() }))
+}
+
+/// Why `from -> to` is in the graph: `from` was held (taken at `held_at`)
+/// when `to` was taken at `taken_at`.
+#[derive(Clone, Copy)]
+struct Edge {
+    held_at: Site,
+    taken_at: Site,
+}
+
+struct Graph(UnsafeCell<[[Option<Edge>; MAX_CLASSES]; MAX_CLASSES]>);
+
+// Safety: only accessed under GRAPH_LOCK.
+unsafe impl Sync for Graph {}
+
+static GRAPH: Graph = Graph(UnsafeCell::new([[None; MAX_CLASSES]; MAX_CLASSES]));
+/// Plain flag rather than a `SpinLock`, which would recurse into lockdep.
+static GRAPH_LOCK: AtomicBool = AtomicBool::new(false);
+
+static TABLE_FULL: AtomicBool = AtomicBool::new(false);
+static STACK_FULL: AtomicBool = AtomicBool::new(false);
+static RECURSION_REPORTED: [AtomicBool; MAX_CLASSES] =
+    [const { AtomicBool::new(false) }; MAX_CLASSES];
+
+fn warn_once(flag: &AtomicBool, args: core::fmt::Arguments) {
+    if !flag.swap(true, Ordering::Relaxed) {
+        serial_println!("lockdep: {}", args);
+    }
+}
+
+fn with_graph<R>(f: impl FnOnce(&mut [[Option<Edge>; MAX_CLASSES]; MAX_CLASSES]) -> R) -> R {
+    while GRAPH_LOCK
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    let r = f(unsafe { &mut *GRAPH.0.get() });
+    GRAPH_LOCK.store(false, Ordering::Release);
+    r
+}
+
+/// Called before spinning on a lock of `class`.
+#[track_caller]
+pub fn acquire(class: &Class) {
+    let site = Location::caller();
+    let Some(id) = class.id() else {
+        return;
+    };
+    let lock = class as *const Class;
+    with_held(|held| {
+        for h in held.entries[..held.depth].iter().flatten() {
+            if h.lock == lock {
+                report_recursion(id, h, site);
+            } else if h.class != id {
+                add_edge(h, id, site);
+            }
+        }
+        push(
+            held,
+            Held {
+                class: id,
+                lock,
+                site,
+            },
+        );
+    });
+}
+
+/// Called after a successful `try_lock`. A try-lock cannot wait, so it adds
+/// no ordering of its own; it is tracked for the locks taken under it.
+#[track_caller]
+pub fn acquired_try(class: &Class) {
+    let site = Location::caller();
+    if let Some(id) = class.id() {
+        let lock = class as *const Class;
+        with_held(|held| {
+            push(
+                held,
+                Held {
+                    class: id,
+                    lock,
+                    site,
+                },
+            )
+        });
+    }
+}
+
+pub fn release(class: &Class) {
+    if class.id().is_none() {
+        return;
+    }
+    let lock = class as *const Class;
+    with_held(|held| {
+        // usually the top, but guards may be dropped out of order
+        let depth = held.depth;
+        if let Some(i) = (0..depth)
+            .rev()
+            .find(|&i| held.entries[i].is_some_and(|h| h.lock == lock))
+        {
+            held.entries.copy_within(i + 1..depth, i);
+            held.entries[depth - 1] = None;
+            held.depth -= 1;
+        }
+    });
+}
+
+fn push(held: &mut HeldStack, h: Held) {
+    if held.depth == MAX_HELD {
+        warn_once(
+            &STACK_FULL,
+            format_args!("more than {} locks held, not tracking", MAX_HELD),
+        );
+        return;
+    }
+    held.entries[held.depth] = Some(h);
+    held.depth += 1;
+}
+
+fn report_recursion(id: usize, held: &Held, site: Site) {
+    if RECURSION_REPORTED[id].swap(true, Ordering::Relaxed) {
+        return;
+    }
+    serial_println!("");
+    serial_println!(
+        "=== lockdep: recursive locking in thread {} ===",
+        thread::current_id()
+    );
+    serial_println!("  lock class {}", class_site(id));
+    serial_println!("  already held, taken at {}", held.site);
+    serial_println!("  taken again at         {}", site);
+}
+
+fn add_edge(from: &Held, to: usize, site: Site) {
+    with_graph(|graph| {
+        if graph[from.class][to].is_some() {
+            return;
+        }
+        if let Some(path) = find_path(graph, to, from.class) {
+            report_cycle(graph, from, to, site, &path);
+        }
+        graph[from.class][to] = Some(Edge {
+            held_at: from.site,
+            taken_at: site,
+        });
+    });
+}
+
+/// Path `from -> ... -> to` as a list of classes (reversed), if any.
+fn find_path(
+    graph: &[[Option<Edge>; MAX_CLASSES]; MAX_CLASSES],
+    from: usize,
+    to: usize,
+) -> Option<([usize; MAX_CLASSES], usize)> {
+    let mut parent = [NO_CLASS; MAX_CLASSES];
+    let mut queue = [0usize; MAX_CLASSES];
+    let (mut head, mut tail) = (0, 1);
+    queue[0] = from;
+    parent[from] = from;
+    while head < tail {
+        let c = queue[head];
+        head += 1;
+        if c == to {
+            let mut path = [0; MAX_CLASSES];
+            let mut len = 0;
+            let mut n = to;
+            while n != from {
+                path[len] = n;
+                len += 1;
+                n = parent[n];
+            }
+            path[len] = from;
+            return Some((path, len + 1));
+        }
+        for next in 0..MAX_CLASSES {
+            if graph[c][next].is_some() && parent[next] == NO_CLASS {
+                parent[next] = c;
+                queue[tail] = next;
+                tail += 1;
+            }
+        }
+    }
+    None
+}
+
+fn report_cycle(
+    graph: &[[Option<Edge>; MAX_CLASSES]; MAX_CLASSES],
+    from: &Held,
+    to: usize,
+    site: Site,
+    (path, len): &([usize; MAX_CLASSES], usize),
+) {
+    serial_println!("");
+    serial_println!(
+        "=== lockdep: possible circular locking in thread {} ===",
+        thread::current_id()
+    );
+    serial_println!("  taking   {} at {}", class_site(to), site);
+    serial_println!(
+        "  holding  {} taken at {}",
+        class_site(from.class),
+        from.site
+    );
+    serial_println!("  but the opposite order was seen before:");
+    // the path is stored backwards, ending at `to`
+    for i in (1..*len).rev() {
+        let (a, b) = (path[i], path[i - 1]);
+        if let Some(e) = graph[a][b] {
+            serial_println!("    {} held (taken at {})", class_site(a), e.held_at);
+            serial_println!("      -> {} taken at {}", class_site(b), e.taken_at);
+        }
+    }
+}
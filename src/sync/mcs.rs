@@ -0,0 +1,199 @@
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+use super::stats::LockStats;
+use crate::cpu;
+
+/// Queue nodes per CPU: one per MCS lock a CPU can hold (or wait for) at the
+/// same time, counting locks taken from interrupt handlers. Deeper nesting
+/// falls back to the lock's spare node.
+const NODES_PER_CPU: usize = 8;
+
+/// MCS queue lock: each waiter spins on its own node, so a release touches
+/// only the next waiter's cache line, and waiters are served in FIFO order.
+pub struct McsLock<T> {
+    tail: AtomicPtr<Node>,
+    /// Used by a CPU whose pool is empty; CPUs in that state queue for it.
+    spare: Node,
+    stats: LockStats,
+    value: UnsafeCell<T>,
+}
+
+pub struct McsLockGuard<'a, T> {
+    lock: &'a McsLock<T>,
+    node: &'a Node,
+}
+
+struct Node {
+    in_use: AtomicBool,
+    locked: AtomicBool,
+    next: AtomicPtr<Node>,
+}
+
+impl Node {
+    const fn new() -> Self {
+        Self {
+            in_use: AtomicBool::new(false),
+            locked: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn as_ptr(&self) -> *mut Node {
+        self as *const Node as *mut Node
+    }
+
+    fn claim(&self) -> bool {
+        self.in_use
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+static NODES: [[Node; NODES_PER_CPU]; cpu::MAX_CPUS] =
+    [const { [const { Node::new() }; NODES_PER_CPU] }; cpu::MAX_CPUS];
+
+/// A free node from this CPU's pool, else `spare` once no other CPU is using
+/// it (or `None` straight away if `wait` is false). Guards need not be
+/// dropped in order, so this scans rather than keeping a stack depth.
+fn alloc_node(spare: &Node, wait: bool) -> Option<&Node> {
+    let node = match NODES[cpu::id()].iter().find(|n| n.claim()) {
+        Some(node) => node,
+        None => loop {
+            if spare.claim() {
+                break spare;
+            }
+            if !wait {
+                return None;
+            }
+            core::hint::spin_loop();
+        },
+    };
+    node.next.store(ptr::null_mut(), Ordering::Relaxed);
+    node.locked.store(true, Ordering::Relaxed);
+    Some(node)
+}
+
+fn free_node(node: &Node) {
+    node.in_use.store(false, Ordering::Release);
+}
+
+unsafe impl<T: Send> Sync for McsLock<T> {}
+
+impl<T> McsLock<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        Self {
+            tail: AtomicPtr::new(ptr::null_mut()),
+            spare: Node::new(),
+            stats: LockStats::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> McsLockGuard<'_, T> {
+        cpu::preempt_disable();
+        let node = alloc_node(&self.spare, true).unwrap();
+        let pred = self.tail.swap(node.as_ptr(), Ordering::AcqRel);
+        let mut spins = 0;
+        if !pred.is_null() {
+            // Safety: a node stays in use until its owner has handed the
+            // lock on, which cannot happen before we link ourselves in.
+            unsafe { &*pred }
+                .next
+                .store(node.as_ptr(), Ordering::Release);
+            while node.locked.load(Ordering::Acquire) {
+                spins += 1;
+                core::hint::spin_loop();
+            }
+        }
+        self.stats.record(spins);
+        McsLockGuard { lock: self, node }
+    }
+
+    #[allow(dead_code)]
+    pub fn try_lock(&self) -> Option<McsLockGuard<'_, T>> {
+        cpu::preempt_disable();
+        let Some(node) = alloc_node(&self.spare, false) else {
+            cpu::preempt_enable();
+            return None;
+        };
+        match self.tail.compare_exchange(
+            ptr::null_mut(),
+            node.as_ptr(),
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => {
+                self.stats.record(0);
+                Some(McsLockGuard { lock: self, node })
+            }
+            Err(_) => {
+                free_node(node);
+                cpu::preempt_enable();
+                None
+            }
+        }
+    }
+
+    /// Racy by nature; for diagnostics and assertions only.
+    #[allow(dead_code)]
+    pub fn is_locked(&self) -> bool {
+        !self.tail.load(Ordering::Relaxed).is_null()
+    }
+}
+
+impl<T> Deref for McsLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        // Safety: being at the head of the queue means exclusive access.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for McsLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safety: being at the head of the queue means exclusive access.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for McsLockGuard<'_, T> {
+    fn drop(&mut self) {
+        let node = self.node;
+        let mut next = node.next.load(Ordering::Acquire);
+        if next.is_null() {
+            // nobody queued behind us: try to leave the lock free
+            if self
+                .lock
+                .tail
+                .compare_exchange(
+                    node.as_ptr(),
+                    ptr::null_mut(),
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                free_node(node);
+                cpu::preempt_enable();
+                return;
+            }
+            // a waiter swapped itself in but has not linked up yet
+            loop {
+                next = node.next.load(Ordering::Acquire);
+                if !next.is_null() {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+        unsafe { &*next }.locked.store(false, Ordering::Release);
+        free_node(node);
+        cpu::preempt_enable();
+    }
+}
@@ -0,0 +1,93 @@
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::sync::waitqueue::WaitQueue;
+use crate::thread::{self, ThreadId};
+
+/// Sleeping lock: a contended `lock` puts the thread to sleep instead of
+/// spinning, so it suits long critical sections. Thread context only; use
+/// a spinlock for data shared with interrupt handlers.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    owner: AtomicU64,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    #[allow(dead_code)]
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            owner: AtomicU64::new(ThreadId::NONE.as_u64()),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self.owner.load(Ordering::Relaxed) == thread::current_id().as_u64() {
+            panic!("Mutex: recursive lock");
+        }
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            self.waiters
+                .wait_until(|| !self.locked.load(Ordering::Relaxed));
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        self.owner
+            .store(thread::current_id().as_u64(), Ordering::Relaxed);
+        Some(MutexGuard { mutex: self })
+    }
+
+    #[allow(dead_code)]
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    pub(crate) fn mutex(&self) -> &'a Mutex<T> {
+        self.mutex
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex
+            .owner
+            .store(ThreadId::NONE.as_u64(), Ordering::Relaxed);
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
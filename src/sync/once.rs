@@ -0,0 +1,138 @@
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU8, AtomicU64, Ordering},
+};
+
+use crate::sync::waitqueue::WaitQueue;
+use crate::thread::{self, ThreadId};
+use crate::{cpu, irq};
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// Runs an initializer exactly once. Other callers wait for it to finish;
+/// a caller that would wait on its own initializer (the initializer calling
+/// back in, or an interrupt handler that interrupted it) panics instead of
+/// spinning forever. Ownership is per thread, so a thread that preempted the
+/// initializer sleeps until it is done rather than spinning, which would
+/// starve a lower priority initializer. A panicking initializer takes the
+/// kernel down (panics abort), so there is no failed state to report.
+pub struct Once {
+    state: AtomicU8,
+    owner: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Once {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+            owner: AtomicU64::new(ThreadId::NONE.as_u64()),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    pub fn call_once(&self, f: impl FnOnce()) {
+        if self.is_completed() {
+            return;
+        }
+        if self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+            .is_ok()
+        {
+            self.owner
+                .store(thread::current_id().as_u64(), Ordering::Relaxed);
+            f();
+            self.owner.store(ThreadId::NONE.as_u64(), Ordering::Relaxed);
+            self.state.store(COMPLETE, Ordering::Release);
+            self.waiters.wake_all();
+            return;
+        }
+        self.wait();
+    }
+
+    fn wait(&self) {
+        let recursive = || self.owner.load(Ordering::Relaxed) == thread::current_id().as_u64();
+        if cpu::preemptible() && irq::enabled() && !recursive() {
+            self.waiters.wait_until(|| self.is_completed());
+            return;
+        }
+        // atomic context cannot sleep
+        loop {
+            match self.state.load(Ordering::Acquire) {
+                COMPLETE => return,
+                _ if recursive() => panic!("Once: recursive initialization"),
+                _ => core::hint::spin_loop(),
+            }
+        }
+    }
+}
+
+/// A value set once at runtime, e.g. from boot information.
+pub struct OnceCell<T> {
+    once: Once,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            once: Once::new(),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.once
+            .is_completed()
+            .then(|| unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    /// Stores `value` unless the cell is already set; gives it back if so.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.once.call_once(|| {
+            let v = value.take().unwrap();
+            unsafe { (*self.value.get()).write(v) };
+        });
+        match value {
+            None => Ok(()),
+            Some(v) => Err(v),
+        }
+    }
+
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        self.once.call_once(|| {
+            let v = f();
+            unsafe { (*self.value.get()).write(v) };
+        });
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    /// Empties the cell so it can be set again.
+    #[allow(dead_code)]
+    pub fn take(&mut self) -> Option<T> {
+        if !self.once.is_completed() {
+            return None;
+        }
+        self.once = Once::new();
+        Some(unsafe { self.value.get_mut().assume_init_read() })
+    }
+}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        if self.once.is_completed() {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
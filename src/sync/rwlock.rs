@@ -0,0 +1,143 @@
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::cpu;
+
+const WRITER: u32 = 1 << 31;
+
+/// Reader-writer spinlock for data that is read constantly and written
+/// rarely. Writers are preferred: once one is waiting, new readers hold off,
+/// so a steady stream of readers cannot starve it.
+///
+/// Because of that, a reader must not take the same lock again while holding
+/// it (for example from an interrupt handler on the same CPU) if a writer on
+/// another CPU may be waiting in between.
+pub struct RwSpinLock<T> {
+    /// `WRITER` while write-locked, otherwise the number of readers.
+    state: AtomicU32,
+    writers_waiting: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+pub struct RwSpinLockReadGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+}
+
+pub struct RwSpinLockWriteGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwSpinLock<T> {}
+
+impl<T> RwSpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            writers_waiting: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> RwSpinLockReadGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwSpinLockReadGuard<'_, T>> {
+        if self.writers_waiting.load(Ordering::Relaxed) != 0 {
+            return None;
+        }
+        let state = self.state.load(Ordering::Relaxed);
+        if state & WRITER != 0 {
+            return None;
+        }
+        cpu::preempt_disable();
+        if self
+            .state
+            .compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            cpu::preempt_enable();
+            return None;
+        }
+        Some(RwSpinLockReadGuard { lock: self })
+    }
+
+    pub fn write(&self) -> RwSpinLockWriteGuard<'_, T> {
+        cpu::preempt_disable();
+        self.writers_waiting.fetch_add(1, Ordering::Relaxed);
+        while self
+            .state
+            .compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        self.writers_waiting.fetch_sub(1, Ordering::Relaxed);
+        RwSpinLockWriteGuard { lock: self }
+    }
+
+    #[allow(dead_code)]
+    pub fn try_write(&self) -> Option<RwSpinLockWriteGuard<'_, T>> {
+        cpu::preempt_disable();
+        if self
+            .state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            cpu::preempt_enable();
+            return None;
+        }
+        Some(RwSpinLockWriteGuard { lock: self })
+    }
+
+    /// Racy by nature; for diagnostics and assertions only.
+    #[allow(dead_code)]
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Ordering::Relaxed) != 0
+    }
+}
+
+impl<T> Deref for RwSpinLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        // Safety: no writer can get in while any read guard exists.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwSpinLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        cpu::preempt_enable();
+    }
+}
+
+impl<T> Deref for RwSpinLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        // Safety: the write guard is exclusive.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwSpinLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safety: the write guard is exclusive.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwSpinLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        cpu::preempt_enable();
+    }
+}
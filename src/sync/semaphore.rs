@@ -0,0 +1,47 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sync::waitqueue::WaitQueue;
+
+/// Counting semaphore. `acquire` sleeps while the count is zero; `release`
+/// may be called from interrupt handlers, e.g. to signal a completed
+/// transfer to the thread waiting for it.
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    #[allow(dead_code)]
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            self.waiters
+                .wait_until(|| self.count.load(Ordering::Relaxed) > 0);
+        }
+    }
+
+    /// Takes one unit without sleeping, if there is one.
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |c| c.checked_sub(1))
+            .is_ok()
+    }
+
+    #[allow(dead_code)]
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    #[allow(dead_code)]
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
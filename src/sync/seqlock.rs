@@ -0,0 +1,70 @@
+use core::{
+    cell::UnsafeCell,
+    ptr,
+    sync::atomic::{AtomicU32, Ordering, fence},
+};
+
+use crate::irq;
+
+/// Sequence lock for small `Copy` data: readers never write shared memory,
+/// they copy the value and retry if a writer was active meanwhile. Suits
+/// state that is read on hot paths and updated rarely, like clock parameters.
+pub struct SeqLock<T: Copy> {
+    /// Odd while a write is in progress.
+    seq: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> T {
+        loop {
+            let start = self.seq.load(Ordering::Acquire);
+            if start & 1 != 0 {
+                core::hint::spin_loop();
+                continue;
+            }
+            // may observe a torn value; it is thrown away below if so
+            let value = unsafe { ptr::read_volatile(self.value.get()) };
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == start {
+                return value;
+            }
+        }
+    }
+
+    /// Replaces the value. Interrupts are off for the duration: a reader in
+    /// an interrupt handler on this CPU would otherwise retry forever.
+    pub fn write(&self, value: T) {
+        irq::without_interrupts(|| {
+            let mut seq = self.seq.load(Ordering::Relaxed);
+            loop {
+                if seq & 1 == 0 {
+                    match self.seq.compare_exchange_weak(
+                        seq,
+                        seq.wrapping_add(1),
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => break,
+                        Err(cur) => seq = cur,
+                    }
+                } else {
+                    core::hint::spin_loop();
+                    seq = self.seq.load(Ordering::Relaxed);
+                }
+            }
+            fence(Ordering::Release);
+            unsafe { ptr::write_volatile(self.value.get(), value) };
+            self.seq.store(seq.wrapping_add(2), Ordering::Release);
+        });
+    }
+}
@@ -4,8 +4,14 @@ use core::{
     sync::atomic::{AtomicBool, Ordering},
 };
 
+#[cfg(feature = "lockdep")]
+use super::lockdep;
+use crate::cpu;
+
 pub struct SpinLock<T> {
     locked: AtomicBool,
+    #[cfg(feature = "lockdep")]
+    class: lockdep::Class,
     value: UnsafeCell<T>,
 }
 
@@ -15,15 +21,24 @@ pub struct SpinLockGuard<'a, T> {
 
 unsafe impl<T: Send> Sync for SpinLock<T> {}
 
+// with lockdep, construction and lock sites are the caller's, so reports
+// point at real code rather than at this file
 impl<T> SpinLock<T> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
     pub const fn new(value: T) -> Self {
         Self {
             locked: AtomicBool::new(false),
+            #[cfg(feature = "lockdep")]
+            class: lockdep::Class::new(),
             value: UnsafeCell::new(value),
         }
     }
 
+    #[cfg_attr(feature = "lockdep", track_caller)]
     pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(&self.class);
+        cpu::preempt_disable();
         while self
             .locked
             .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
@@ -33,6 +48,27 @@ impl<T> SpinLock<T> {
         }
         SpinLockGuard { lock: self }
     }
+
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        cpu::preempt_disable();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            cpu::preempt_enable();
+            return None;
+        }
+        #[cfg(feature = "lockdep")]
+        lockdep::acquired_try(&self.class);
+        Some(SpinLockGuard { lock: self })
+    }
+
+    /// Racy by nature; for diagnostics and assertions only.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
 }
 
 impl<T> Deref for SpinLockGuard<'_, T> {
@@ -54,6 +90,9 @@ impl<T> DerefMut for SpinLockGuard<'_, T> {
 
 impl<T> Drop for SpinLockGuard<'_, T> {
     fn drop(&mut self) {
+        #[cfg(feature = "lockdep")]
+        lockdep::release(&self.lock.class);
         self.lock.locked.store(false, Ordering::Release);
+        cpu::preempt_enable();
     }
 }
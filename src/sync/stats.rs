@@ -0,0 +1,123 @@
+// Contention counters for the fair locks, aggregated per construction site so
+// every instance of e.g. a per-object lock adds up to one line in the dump.
+
+use core::fmt;
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+const MAX_SITES: usize = 64;
+const NO_SLOT: usize = usize::MAX;
+const FULL: usize = usize::MAX - 1;
+
+struct Site {
+    location: AtomicPtr<Location<'static>>,
+    acquisitions: AtomicU64,
+    contended: AtomicU64,
+    spins: AtomicU64,
+}
+
+impl Site {
+    const fn new() -> Self {
+        Self {
+            location: AtomicPtr::new(ptr::null_mut()),
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            spins: AtomicU64::new(0),
+        }
+    }
+}
+
+static SITES: [Site; MAX_SITES] = [const { Site::new() }; MAX_SITES];
+/// Acquisitions not counted because every slot was taken.
+static UNTRACKED: AtomicU64 = AtomicU64::new(0);
+
+/// Per-lock handle into the site table; the slot is claimed on first use.
+pub struct LockStats {
+    site: &'static Location<'static>,
+    slot: AtomicUsize,
+}
+
+impl LockStats {
+    #[track_caller]
+    pub const fn new() -> Self {
+        Self {
+            site: Location::caller(),
+            slot: AtomicUsize::new(NO_SLOT),
+        }
+    }
+
+    /// Where the lock was constructed.
+    #[allow(dead_code)]
+    pub fn site(&self) -> &'static Location<'static> {
+        self.site
+    }
+
+    fn slot(&self) -> Option<&'static Site> {
+        let mut slot = self.slot.load(Ordering::Relaxed);
+        if slot == NO_SLOT {
+            slot = claim(self.site);
+            self.slot.store(slot, Ordering::Relaxed);
+        }
+        SITES.get(slot)
+    }
+
+    /// Counts one acquisition; `spins` is how long the caller waited, 0 if
+    /// the lock was free.
+    pub fn record(&self, spins: u64) {
+        let Some(site) = self.slot() else {
+            UNTRACKED.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        site.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if spins > 0 {
+            site.contended.fetch_add(1, Ordering::Relaxed);
+            site.spins.fetch_add(spins, Ordering::Relaxed);
+        }
+    }
+}
+
+fn claim(location: &'static Location<'static>) -> usize {
+    let want = location as *const _ as *mut Location<'static>;
+    for (i, site) in SITES.iter().enumerate() {
+        match site.location.compare_exchange(
+            ptr::null_mut(),
+            want,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => return i,
+            Err(cur) if cur == want => return i,
+            Err(_) => {}
+        }
+    }
+    FULL
+}
+
+/// Writes one line per lock site with `emit`, in order of first use.
+pub fn dump(mut emit: impl FnMut(fmt::Arguments)) {
+    emit(format_args!(
+        "--- lock stats (acquisitions contended spins site) ---\n"
+    ));
+    for site in &SITES {
+        let location = site.location.load(Ordering::Acquire);
+        if location.is_null() {
+            break;
+        }
+        emit(format_args!(
+            "{:>12} {:>10} {:>14} {}\n",
+            site.acquisitions.load(Ordering::Relaxed),
+            site.contended.load(Ordering::Relaxed),
+            site.spins.load(Ordering::Relaxed),
+            unsafe { &*location }
+        ));
+    }
+    let untracked = UNTRACKED.load(Ordering::Relaxed);
+    if untracked > 0 {
+        emit(format_args!(
+            "{} acquisitions at untracked sites\n",
+            untracked
+        ));
+    }
+    emit(format_args!("--- end lock stats ---\n"));
+}
@@ -0,0 +1,101 @@
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use super::stats::LockStats;
+use crate::cpu;
+
+/// FIFO spinlock: waiters take a ticket and are served in order, so nobody
+/// starves. Every waiter still polls the same cache line; prefer `McsLock`
+/// for locks that see heavy contention.
+pub struct TicketLock<T> {
+    next: AtomicU32,
+    serving: AtomicU32,
+    stats: LockStats,
+    value: UnsafeCell<T>,
+}
+
+pub struct TicketLockGuard<'a, T> {
+    lock: &'a TicketLock<T>,
+}
+
+unsafe impl<T: Send> Sync for TicketLock<T> {}
+
+impl<T> TicketLock<T> {
+    #[track_caller]
+    #[allow(dead_code)]
+    pub const fn new(value: T) -> Self {
+        Self {
+            next: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+            stats: LockStats::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        cpu::preempt_disable();
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut spins = 0;
+        while self.serving.load(Ordering::Acquire) != ticket {
+            spins += 1;
+            core::hint::spin_loop();
+        }
+        self.stats.record(spins);
+        TicketLockGuard { lock: self }
+    }
+
+    #[allow(dead_code)]
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        cpu::preempt_disable();
+        let serving = self.serving.load(Ordering::Relaxed);
+        if self
+            .next
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            cpu::preempt_enable();
+            return None;
+        }
+        self.stats.record(0);
+        Some(TicketLockGuard { lock: self })
+    }
+
+    /// Racy by nature; for diagnostics and assertions only.
+    #[allow(dead_code)]
+    pub fn is_locked(&self) -> bool {
+        self.next.load(Ordering::Relaxed) != self.serving.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        // Safety: holding the current ticket means exclusive access.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // Safety: holding the current ticket means exclusive access.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // only the holder ever writes `serving`
+        let next = self.lock.serving.load(Ordering::Relaxed).wrapping_add(1);
+        self.lock.serving.store(next, Ordering::Release);
+        cpu::preempt_enable();
+    }
+}
@@ -0,0 +1,110 @@
+use crate::sync::irq_spinlock::IrqSpinLock;
+use crate::thread::{self, MAX_THREADS, ThreadId};
+
+/// Threads sleeping until some event. Waiting needs thread context; the
+/// `wake_*` side may run anywhere, including interrupt handlers, so a driver
+/// can sleep until its completion interrupt.
+pub struct WaitQueue {
+    waiters: IrqSpinLock<Waiters>,
+}
+
+/// FIFO of sleeping threads; each thread is in it at most once.
+struct Waiters {
+    ids: [ThreadId; MAX_THREADS],
+    len: usize,
+}
+
+impl Waiters {
+    fn push(&mut self, id: ThreadId) {
+        if !self.ids[..self.len].contains(&id) {
+            self.ids[self.len] = id;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<ThreadId> {
+        if self.len == 0 {
+            return None;
+        }
+        let id = self.ids[0];
+        self.ids.copy_within(1..self.len, 0);
+        self.len -= 1;
+        Some(id)
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        if let Some(i) = self.ids[..self.len].iter().position(|&w| w == id) {
+            self.ids.copy_within(i + 1..self.len, i);
+            self.len -= 1;
+        }
+    }
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: IrqSpinLock::new(Waiters {
+                ids: [ThreadId::NONE; MAX_THREADS],
+                len: 0,
+            }),
+        }
+    }
+
+    /// Sleeps until `cond` holds. `cond` is checked after queueing, so a
+    /// wakeup between the check and going to sleep is not missed.
+    pub fn wait_until(&self, mut cond: impl FnMut() -> bool) {
+        let id = thread::current_id();
+        loop {
+            if cond() {
+                return;
+            }
+            self.waiters.lock().push(id);
+            if cond() {
+                self.waiters.lock().remove(id);
+                return;
+            }
+            thread::block();
+        }
+    }
+
+    /// Sleeps until the next `wake_one`/`wake_all` (or a spurious wakeup).
+    #[allow(dead_code)]
+    pub fn wait(&self) {
+        self.prepare_to_wait();
+        thread::block();
+        self.finish_wait();
+    }
+
+    /// Queues the current thread without sleeping yet; `thread::block`
+    /// afterwards returns at once if a wakeup came in between.
+    pub(crate) fn prepare_to_wait(&self) {
+        self.waiters.lock().push(thread::current_id());
+    }
+
+    /// Leaves the queue after a wakeup that may not have come from it.
+    pub(crate) fn finish_wait(&self) {
+        self.waiters.lock().remove(thread::current_id());
+    }
+
+    /// Wakes the longest waiting thread. Returns false if nobody waited.
+    pub fn wake_one(&self) -> bool {
+        loop {
+            let Some(id) = self.waiters.lock().pop() else {
+                return false;
+            };
+            // skip threads that exited while queued
+            if thread::wake(id) {
+                return true;
+            }
+        }
+    }
+
+    /// Wakes every waiting thread and returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let mut woken = 0;
+        while self.wake_one() {
+            woken += 1;
+        }
+        woken
+    }
+}
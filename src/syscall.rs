@@ -0,0 +1,480 @@
+// System calls.
+//
+// User code enters through SYSCALL (or `int 0x80`) with the number in rax
+// and arguments in rdi, rsi, rdx, r10, r8, r9; the result comes back in rax,
+// negative errno on failure. Numbers and semantics follow Linux x86_64 so
+// existing toolchains can target us; unknown numbers fail with ENOSYS.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::frame_alloc::PAGE_SIZE;
+use crate::paging::{self, USER_END};
+use crate::process::{
+    self, File, PROT_EXEC, PROT_READ, PROT_WRITE, Process, SpawnError, Vma, VmaKind,
+};
+use crate::usermode::UserRegs;
+use crate::{cpu, gdt, idt, modules, thread};
+
+pub const SYS_READ: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_OPEN: u64 = 2;
+pub const SYS_CLOSE: u64 = 3;
+pub const SYS_MMAP: u64 = 9;
+pub const SYS_MPROTECT: u64 = 10;
+pub const SYS_MUNMAP: u64 = 11;
+pub const SYS_BRK: u64 = 12;
+pub const SYS_SCHED_YIELD: u64 = 24;
+pub const SYS_NANOSLEEP: u64 = 35;
+pub const SYS_GETPID: u64 = 39;
+pub const SYS_FORK: u64 = 57;
+pub const SYS_EXIT: u64 = 60;
+pub const SYS_EXIT_GROUP: u64 = 231;
+
+const ENOENT: i64 = 2;
+const EBADF: i64 = 9;
+const EAGAIN: i64 = 11;
+const ENOMEM: i64 = 12;
+const EACCES: i64 = 13;
+const EFAULT: i64 = 14;
+const ENODEV: i64 = 19;
+const EINVAL: i64 = 22;
+const EMFILE: i64 = 24;
+const ENAMETOOLONG: i64 = 36;
+const ENOSYS: i64 = 38;
+
+const O_ACCMODE: u64 = 0x3;
+const O_RDONLY: u64 = 0x0;
+/// Longest path `open` takes, NUL included.
+const MAX_PATH: usize = 256;
+/// `read` and `write` touch user buffers this much at a time.
+const USER_CHUNK: u64 = PAGE_SIZE;
+
+const MAP_SHARED: u64 = 0x01;
+const MAP_PRIVATE: u64 = 0x02;
+const MAP_TYPE: u64 = 0x0F;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const MSR_EFER: u32 = 0xC000_0080;
+const MSR_STAR: u32 = 0xC000_0081;
+const MSR_LSTAR: u32 = 0xC000_0082;
+const MSR_SFMASK: u32 = 0xC000_0084;
+const MSR_KERNEL_GS_BASE: u32 = 0xC000_0102;
+const EFER_SCE: u64 = 1 << 0;
+/// TF, IF, DF and AC are cleared on entry.
+const SFMASK: u64 = 0x0004_0700;
+
+const INT80_VECTOR: u8 = 0x80;
+
+type Result = core::result::Result<u64, i64>;
+
+/// What the entry stubs save: all of the user registers (see syscall.S).
+#[repr(C)]
+pub struct SyscallRegs {
+    nr: u64,
+    args: [u64; 6],
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rcx: u64,
+    r11: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+impl SyscallRegs {
+    /// The state to resume user mode in, `rax` being the return value.
+    fn user_regs(&self, rax: u64) -> UserRegs {
+        let [rdi, rsi, rdx, r10, r8, r9] = self.args;
+        UserRegs {
+            rax,
+            rbx: self.rbx,
+            rcx: self.rcx,
+            rdx,
+            rsi,
+            rdi,
+            rbp: self.rbp,
+            r8,
+            r9,
+            r10,
+            r11: self.r11,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.rip,
+            rflags: self.rflags,
+            rsp: self.rsp,
+            ..UserRegs::default()
+        }
+    }
+}
+
+/// Per-CPU block at KERNEL_GS_BASE, read by `syscall_entry`.
+#[repr(C)]
+struct EntryArea {
+    kernel_stack: AtomicU64,
+    user_rsp: AtomicU64,
+}
+
+static ENTRY_AREA: [EntryArea; cpu::MAX_CPUS] = [const {
+    EntryArea {
+        kernel_stack: AtomicU64::new(0),
+        user_rsp: AtomicU64::new(0),
+    }
+}; cpu::MAX_CPUS];
+
+unsafe extern "C" {
+    fn syscall_entry();
+    fn syscall_int80();
+}
+
+/// Enables SYSCALL/SYSRET on this CPU and installs the `int 0x80` gate.
+pub fn init() {
+    unsafe {
+        // SYSCALL loads CS from STAR[47:32] and SS from +8; SYSRET loads SS
+        // from STAR[63:48] + 8 and CS from + 16, with RPL 3 (see gdt.rs)
+        let star = ((gdt::KERNEL_DS as u64) << 48) | ((gdt::KERNEL_CS as u64) << 32);
+        cpu::wrmsr(MSR_STAR, star);
+        cpu::wrmsr(
+            MSR_LSTAR,
+            syscall_entry as unsafe extern "C" fn() as usize as u64,
+        );
+        cpu::wrmsr(MSR_SFMASK, SFMASK);
+        cpu::wrmsr(
+            MSR_KERNEL_GS_BASE,
+            &ENTRY_AREA[cpu::id()] as *const EntryArea as u64,
+        );
+        cpu::wrmsr(MSR_EFER, cpu::rdmsr(MSR_EFER) | EFER_SCE);
+    }
+    idt::set_user_gate(INT80_VECTOR, syscall_int80);
+}
+
+/// Kernel stack `syscall_entry` switches to; kept in step with TSS.rsp0 by
+/// the scheduler.
+pub fn set_kernel_stack(top: u64) {
+    ENTRY_AREA[cpu::id()]
+        .kernel_stack
+        .store(top, Ordering::Relaxed);
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn rust_syscall(regs: &SyscallRegs) -> u64 {
+    let [a0, a1, a2, a3, a4, a5] = regs.args;
+    let result = match regs.nr {
+        SYS_READ => sys_read(a0, a1, a2),
+        SYS_WRITE => sys_write(a0, a1, a2),
+        SYS_OPEN => sys_open(a0, a1),
+        SYS_CLOSE => sys_close(a0),
+        SYS_MMAP => sys_mmap(a0, a1, a2, a3, a4, a5),
+        SYS_MPROTECT => sys_mprotect(a0, a1, a2),
+        SYS_MUNMAP => sys_munmap(a0, a1),
+        SYS_BRK => with_process(|p| Ok(p.set_brk(a0))),
+        SYS_SCHED_YIELD => {
+            thread::yield_now();
+            Ok(0)
+        }
+        SYS_NANOSLEEP => sys_nanosleep(a0),
+        SYS_GETPID => process::current().map(|pid| pid.as_u64()).ok_or(ENOSYS),
+        SYS_FORK => sys_fork(regs),
+        // processes have a single thread, so exiting it ends the process
+        SYS_EXIT | SYS_EXIT_GROUP => thread::exit(),
+        _ => Err(ENOSYS),
+    };
+    match result {
+        Ok(v) => v,
+        Err(errno) => (-errno) as u64,
+    }
+}
+
+fn user_bytes(ptr: u64, len: u64) -> core::result::Result<&'static [u8], i64> {
+    if !process::fault_in(ptr, len, false) {
+        return Err(EFAULT);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+fn user_bytes_mut(ptr: u64, len: u64) -> core::result::Result<&'static mut [u8], i64> {
+    if !process::fault_in(ptr, len, true) {
+        return Err(EFAULT);
+    }
+    Ok(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len as usize) })
+}
+
+/// Copies the NUL-terminated string at `ptr` into `buf`.
+fn user_str(ptr: u64, buf: &mut [u8]) -> core::result::Result<&str, i64> {
+    for i in 0..buf.len() {
+        let addr = ptr.checked_add(i as u64).ok_or(EFAULT)?;
+        // a page at a time: the string may end before the next one
+        if i == 0 || addr.is_multiple_of(PAGE_SIZE) {
+            user_bytes(addr, 1)?;
+        }
+        let byte = unsafe { *(addr as *const u8) };
+        if byte == 0 {
+            return core::str::from_utf8(&buf[..i]).map_err(|_| ENOENT);
+        }
+        buf[i] = byte;
+    }
+    Err(ENAMETOOLONG)
+}
+
+/// Runs `f` on the current process with the process table locked.
+fn with_process<R>(
+    f: impl FnOnce(&mut Process) -> core::result::Result<R, i64>,
+) -> core::result::Result<R, i64> {
+    process::with_current(f).unwrap_or(Err(ENOSYS))
+}
+
+/// The open file behind `fd` in the current process. A copy: I/O on it may
+/// block, which it must not do with the process table locked.
+fn file(fd: u64) -> core::result::Result<File, i64> {
+    process::with_current(|p| p.files.get(fd as usize).copied().flatten())
+        .flatten()
+        .ok_or(EBADF)
+}
+
+/// Length of the piece of `ptr..ptr + len` up to the next chunk boundary.
+fn chunk_len(ptr: u64, len: u64) -> u64 {
+    let boundary = (ptr | (USER_CHUNK - 1)).wrapping_add(1);
+    len.min(boundary.wrapping_sub(ptr))
+}
+
+/// Reads at most up to the end of the chunk `buf` starts in: a short read,
+/// but a huge buffer is not populated for the few bytes that arrive.
+fn sys_read(fd: u64, buf: u64, len: u64) -> Result {
+    let file = file(fd)?;
+    let buf = user_bytes_mut(buf, chunk_len(buf, len))?;
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    file.read(buf).map(|n| n as u64).ok_or(EBADF)
+}
+
+/// Writes a chunk at a time, faulting in each one just before it is used.
+fn sys_write(fd: u64, buf: u64, len: u64) -> Result {
+    let file = file(fd)?;
+    let mut done = 0;
+    while done < len {
+        let ptr = buf.checked_add(done).ok_or(EFAULT)?;
+        // at least one whole UTF-8 sequence, for `utf8_cut`
+        let chunk = user_bytes(ptr, chunk_len(ptr, len - done).max(4).min(len - done))?;
+        let last = done + chunk.len() as u64 == len;
+        let chunk = if last {
+            chunk
+        } else {
+            &chunk[..utf8_cut(chunk)]
+        };
+        let n = file.write(chunk).ok_or(EBADF)?;
+        done += n as u64;
+        if n < chunk.len() {
+            break;
+        }
+    }
+    Ok(done)
+}
+
+/// Where to end a chunk so a UTF-8 sequence running past it is written
+/// whole with the next one.
+fn utf8_cut(chunk: &[u8]) -> usize {
+    let tail = chunk.utf8_chunks().last().map_or(0, |c| c.invalid().len());
+    let start = chunk.len() - tail;
+    match core::str::from_utf8(&chunk[start..]) {
+        // incomplete rather than invalid, and not the whole chunk
+        Err(e) if e.error_len().is_none() && start > 0 => start,
+        _ => chunk.len(),
+    }
+}
+
+/// Paths name boot modules; there is no file system yet. Read-only.
+fn sys_open(path: u64, flags: u64) -> Result {
+    if flags & O_ACCMODE != O_RDONLY {
+        return Err(EACCES);
+    }
+    let mut buf = [0; MAX_PATH];
+    let module = modules::find(user_str(path, &mut buf)?).ok_or(ENOENT)?;
+    with_process(|p| {
+        p.open(File::Module(module.data))
+            .map(|fd| fd as u64)
+            .ok_or(EMFILE)
+    })
+}
+
+fn sys_close(fd: u64) -> Result {
+    match process::with_current(|p| p.close(fd as usize)) {
+        Some(true) => Ok(0),
+        _ => Err(EBADF),
+    }
+}
+
+/// `addr..addr + len` rounded out to whole pages, if `addr` is page aligned
+/// and all of it lies in user space.
+fn user_range(addr: u64, len: u64) -> core::result::Result<(u64, u64), i64> {
+    if !addr.is_multiple_of(PAGE_SIZE) || len == 0 {
+        return Err(EINVAL);
+    }
+    let end = addr
+        .checked_add(len)
+        .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE))
+        .ok_or(EINVAL)?;
+    if addr < paging::USER_BASE || end > USER_END {
+        return Err(EINVAL);
+    }
+    Ok((addr, end))
+}
+
+fn check_prot(prot: u64) -> core::result::Result<u32, i64> {
+    if prot & !u64::from(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(EINVAL);
+    }
+    Ok(prot as u32)
+}
+
+/// Anonymous memory and boot modules (see `sys_open`). Only the region is
+/// recorded and pages come on first touch, except for shared anonymous
+/// memory, which has to exist before a fork can share it. Modules are
+/// read-only, so shared writable mappings of them are refused; private ones
+/// get copies.
+fn sys_mmap(addr: u64, len: u64, prot: u64, flags: u64, fd: u64, offset: u64) -> Result {
+    let shared = match flags & MAP_TYPE {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => return Err(EINVAL),
+    };
+    let prot = check_prot(prot)?;
+    if len == 0 {
+        return Err(EINVAL);
+    }
+    let len = len.checked_next_multiple_of(PAGE_SIZE).ok_or(ENOMEM)?;
+    let kind = if flags & MAP_ANONYMOUS != 0 {
+        VmaKind::Anonymous
+    } else {
+        if !offset.is_multiple_of(PAGE_SIZE) {
+            return Err(EINVAL);
+        }
+        let File::Module(data) = file(fd)? else {
+            return Err(ENODEV);
+        };
+        if offset > data.len() as u64 {
+            return Err(EINVAL);
+        }
+        if shared && prot & PROT_WRITE != 0 {
+            return Err(EACCES);
+        }
+        VmaKind::File { data, offset }
+    };
+    let fixed = flags & MAP_FIXED != 0;
+    if fixed {
+        user_range(addr, len)?;
+    }
+    with_process(|p| {
+        let start = if fixed {
+            // replaces whatever was there
+            if !p.unmap(addr, addr + len) {
+                return Err(ENOMEM);
+            }
+            addr
+        } else if user_range(addr, len).is_ok() && p.is_free(addr, addr + len) {
+            addr
+        } else {
+            p.find_free(len).ok_or(ENOMEM)?
+        };
+        let vma = Vma {
+            start,
+            end: start + len,
+            prot,
+            shared,
+            kind,
+        };
+        if !p.add_vma(vma) {
+            return Err(ENOMEM);
+        }
+        if shared && kind == VmaKind::Anonymous && p.populate(&vma).is_err() {
+            p.unmap(vma.start, vma.end);
+            return Err(ENOMEM);
+        }
+        Ok(start)
+    })
+}
+
+fn sys_munmap(addr: u64, len: u64) -> Result {
+    let (start, end) = user_range(addr, len)?;
+    with_process(|p| {
+        if !p.unmap(start, end) {
+            return Err(ENOMEM);
+        }
+        Ok(0)
+    })
+}
+
+fn sys_mprotect(addr: u64, len: u64, prot: u64) -> Result {
+    let prot = check_prot(prot)?;
+    if !addr.is_multiple_of(PAGE_SIZE) {
+        return Err(EINVAL);
+    }
+    if len == 0 {
+        return Ok(0);
+    }
+    let (start, end) = user_range(addr, len).map_err(|_| ENOMEM)?;
+    with_process(|p| {
+        if !p.is_mapped(start, end) {
+            return Err(ENOMEM);
+        }
+        // shared module mappings stay read-only, as at mmap time
+        let file_shared = p
+            .vmas()
+            .iter()
+            .filter(|v| v.start < end && start < v.end)
+            .any(|v| v.shared && matches!(v.kind, VmaKind::File { .. }));
+        if file_shared && prot & PROT_WRITE != 0 {
+            return Err(EACCES);
+        }
+        if !p.protect(start, end, prot) {
+            return Err(ENOMEM);
+        }
+        Ok(0)
+    })
+}
+
+/// The child returns 0 from the same call.
+fn sys_fork(regs: &SyscallRegs) -> Result {
+    // the child starts through `usermode::enter`, which only takes user
+    // addresses; rsp in particular is whatever user code left in it
+    let child = regs.user_regs(0);
+    if !child.in_user_space() {
+        return Err(EFAULT);
+    }
+    match process::fork(&child) {
+        Ok(pid) => Ok(pid.as_u64()),
+        Err(SpawnError::TableFull) => Err(EAGAIN),
+        Err(_) => Err(ENOMEM),
+    }
+}
+
+/// `struct timespec`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Timespec {
+    sec: i64,
+    nsec: i64,
+}
+
+fn sys_nanosleep(req: u64) -> Result {
+    let bytes = user_bytes(req, size_of::<Timespec>() as u64)?;
+    let ts = unsafe { (bytes.as_ptr() as *const Timespec).read_unaligned() };
+    if ts.sec < 0 || !(0..1_000_000_000).contains(&ts.nsec) {
+        return Err(EINVAL);
+    }
+    let ms = (ts.sec as u64)
+        .saturating_mul(1000)
+        .saturating_add((ts.nsec as u64).div_ceil(1_000_000));
+    if ms > 0 {
+        thread::sleep_ms(ms);
+    }
+    Ok(0)
+}
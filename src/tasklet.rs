@@ -0,0 +1,97 @@
+// Tasklets: deferred halves of interrupt handlers.
+//
+// A handler does the minimum with the device and schedules a tasklet; the
+// tasklet runs on the way out of the interrupt, with interrupts enabled, on
+// the CPU that scheduled it. A tasklet is queued at most once however often
+// it is scheduled before it runs, and never runs on two CPUs at once.
+//
+// Tasklets still run in interrupt context: they must not block. Work that
+// needs to sleep goes to the workqueue instead.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::{cpu, irq};
+
+/// Passes over the pending list per interrupt exit; anything scheduled
+/// after that waits for the next interrupt so a self-rescheduling tasklet
+/// cannot starve threads.
+const MAX_ROUNDS: usize = 10;
+
+pub struct Tasklet {
+    func: fn(usize),
+    data: usize,
+    scheduled: AtomicBool,
+    next: AtomicPtr<Tasklet>,
+}
+
+/// Pending tasklets per CPU, most recently scheduled first.
+static PENDING: [AtomicPtr<Tasklet>; cpu::MAX_CPUS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; cpu::MAX_CPUS];
+/// Set while a CPU is running its tasklets, so an interrupt that comes in
+/// meanwhile leaves them to the outer run.
+static RUNNING: [AtomicBool; cpu::MAX_CPUS] = [const { AtomicBool::new(false) }; cpu::MAX_CPUS];
+
+impl Tasklet {
+    pub const fn new(func: fn(usize), data: usize) -> Self {
+        Self {
+            func,
+            data,
+            scheduled: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Queues the tasklet on this CPU unless it is already queued. Safe from
+    /// any context.
+    pub fn schedule(&'static self) {
+        if self.scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let pending = &PENDING[cpu::id()];
+        let this = self as *const Tasklet as *mut Tasklet;
+        let mut head = pending.load(Ordering::Relaxed);
+        loop {
+            self.next.store(head, Ordering::Relaxed);
+            match pending.compare_exchange_weak(head, this, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(h) => head = h,
+            }
+        }
+    }
+}
+
+/// Runs this CPU's pending tasklets. Called with interrupts off at the end
+/// of every interrupt; returns with them off.
+pub fn run_pending() {
+    let cpu = cpu::id();
+    if RUNNING[cpu].swap(true, Ordering::Acquire) {
+        return;
+    }
+    // like IRQ handlers, tasklets run on the interrupted thread's stack
+    cpu::preempt_disable();
+    for _ in 0..MAX_ROUNDS {
+        let mut list = PENDING[cpu].swap(ptr::null_mut(), Ordering::Acquire);
+        if list.is_null() {
+            break;
+        }
+        // oldest first
+        let mut reversed: *mut Tasklet = ptr::null_mut();
+        while let Some(t) = unsafe { list.as_ref() } {
+            list = t.next.load(Ordering::Relaxed);
+            t.next.store(reversed, Ordering::Relaxed);
+            reversed = t as *const Tasklet as *mut Tasklet;
+        }
+
+        irq::enable();
+        while let Some(t) = unsafe { reversed.as_ref() } {
+            reversed = t.next.load(Ordering::Relaxed);
+            // cleared first: the tasklet may schedule itself again
+            t.scheduled.store(false, Ordering::Release);
+            (t.func)(t.data);
+        }
+        irq::disable();
+    }
+    cpu::preempt_enable();
+    RUNNING[cpu].store(false, Ordering::Release);
+}
@@ -0,0 +1,729 @@
+// Kernel threads and the scheduler.
+//
+// Every thread has a slot in a fixed table and its own stack from the frame
+// allocator; the boot flow of control becomes thread 0 ("main"). The register
+// state of a thread that is not running lives on its own stack, and the slot
+// only keeps the stack pointer (see switch.S).
+//
+// Scheduling is strict priority, round-robin within a level. The timer tick
+// charges the running thread one tick of its time slice; when the slice runs
+// out, or a thread of higher priority becomes ready, the switch happens on
+// the way out of the interrupt, unless the thread holds a spinlock (see
+// `cpu::preempt_disable`). With nothing ready the idle thread halts the CPU.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::frame_alloc::{self, PAGE_SIZE};
+use crate::paging::AddressSpace;
+use crate::process::{self, Pid, SpawnError};
+use crate::sync::irq_spinlock::IrqSpinLock;
+use crate::usermode::{self, UserRegs};
+use crate::{cpu, gdt, irq, syscall, time, timer};
+
+pub const MAX_THREADS: usize = 64;
+const STACK_PAGES: usize = 4;
+const STACK_SIZE: u64 = STACK_PAGES as u64 * PAGE_SIZE;
+/// Ticks a thread may run before others at its priority get a turn.
+const SLICE_TICKS: u32 = 10;
+const PRIORITIES: usize = 3;
+/// Kept at the bottom of every thread stack; checked when the thread is
+/// switched out, as nothing stops an overflow running into the next frame.
+const STACK_CANARY: u64 = 0x5354_4143_4b21_c0de;
+
+unsafe extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_trampoline();
+    /// Top of the boot stack (boot.S), thread 0's kernel stack.
+    static stack_top: u8;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    /// Never a valid id, for "no owner" in lock bookkeeping.
+    pub const NONE: ThreadId = ThreadId(u64::MAX);
+
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    Blocked,
+    Exited,
+}
+
+impl State {
+    fn name(self) -> &'static str {
+        match self {
+            State::Ready => "ready",
+            State::Running => "running",
+            State::Blocked => "blocked",
+            State::Exited => "exited",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    #[allow(dead_code)]
+    High,
+}
+
+impl Priority {
+    fn name(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: State,
+    priority: Priority,
+    entry: fn(),
+    /// Saved stack pointer while not running.
+    rsp: u64,
+    /// Lowest address of the stack, 0 for the boot stack (never freed).
+    stack: u64,
+    /// Nobody will `join`: the slot is reclaimed as soon as it exits.
+    detached: bool,
+    /// Woken before it got to block; the next `block` returns at once.
+    wakeup_pending: bool,
+    /// Thread sleeping in `join` on this one, woken when it exits.
+    joiner: Option<ThreadId>,
+    /// Ticks left in the current time slice.
+    slice: u32,
+    /// TSC cycles spent running, and the TSC when last switched in.
+    runtime: u64,
+    last_run: u64,
+    /// Times switched in.
+    switches: u64,
+    /// Process the thread belongs to; kernel threads have none.
+    process: Option<Pid>,
+    /// Page tables of that process; kernel threads run on the boot tables.
+    space: Option<AddressSpace>,
+    /// Registers of a thread that starts in ring 3.
+    user_start: Option<UserRegs>,
+}
+
+impl Thread {
+    /// Where the CPU enters the kernel when this thread is in user mode.
+    fn kernel_stack_top(&self) -> u64 {
+        if self.stack == 0 {
+            unsafe { &stack_top as *const u8 as u64 }
+        } else {
+            self.stack + STACK_SIZE
+        }
+    }
+
+    fn new(name: &'static str, priority: Priority, entry: fn(), rsp: u64, stack: u64) -> Self {
+        Self {
+            id: ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            name,
+            state: State::Ready,
+            priority,
+            entry,
+            rsp,
+            stack,
+            detached: false,
+            wakeup_pending: false,
+            joiner: None,
+            slice: SLICE_TICKS,
+            runtime: 0,
+            last_run: 0,
+            switches: 0,
+            process: None,
+            space: None,
+            user_start: None,
+        }
+    }
+}
+
+/// FIFO of slot indices.
+struct RunQueue {
+    slots: [usize; MAX_THREADS],
+    head: usize,
+    len: usize,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            slots: [0; MAX_THREADS],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, slot: usize) {
+        let tail = (self.head + self.len) % MAX_THREADS;
+        self.slots[tail] = slot;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let slot = self.slots[self.head];
+        self.head = (self.head + 1) % MAX_THREADS;
+        self.len -= 1;
+        Some(slot)
+    }
+}
+
+struct Table {
+    slots: [Option<Thread>; MAX_THREADS],
+    current: usize,
+    idle: usize,
+    /// Ready threads by priority, lowest first.
+    queues: [RunQueue; PRIORITIES],
+    context_switches: u64,
+}
+
+impl Table {
+    fn thread(&mut self, slot: usize) -> &mut Thread {
+        self.slots[slot].as_mut().expect("empty thread slot")
+    }
+
+    fn current(&mut self) -> &mut Thread {
+        self.thread(self.current)
+    }
+
+    /// Queues `slot` and asks for a reschedule if it should run before the
+    /// current thread.
+    fn make_ready(&mut self, slot: usize) {
+        let t = self.thread(slot);
+        t.state = State::Ready;
+        let priority = t.priority;
+        self.queues[priority as usize].push(slot);
+        if self.current == self.idle || priority > self.current().priority {
+            NEED_RESCHED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// `wake` with the table locked.
+    fn wake(&mut self, id: ThreadId) -> bool {
+        let Some(slot) = self
+            .slots
+            .iter()
+            .position(|t| t.as_ref().is_some_and(|t| t.id == id))
+        else {
+            return false;
+        };
+        let t = self.thread(slot);
+        match t.state {
+            State::Blocked => self.make_ready(slot),
+            State::Exited => return false,
+            _ => t.wakeup_pending = true,
+        }
+        true
+    }
+
+    fn pop_ready(&mut self) -> Option<usize> {
+        self.queues.iter_mut().rev().find_map(|q| q.pop())
+    }
+
+    fn has_ready(&self, at_least: Priority) -> bool {
+        self.queues[at_least as usize..].iter().any(|q| q.len > 0)
+    }
+}
+
+const NO_THREAD: Option<Thread> = None;
+
+static THREADS: IrqSpinLock<Table> = IrqSpinLock::new(Table {
+    slots: [NO_THREAD; MAX_THREADS],
+    current: 0,
+    idle: 0,
+    queues: [const { RunQueue::new() }; PRIORITIES],
+    context_switches: 0,
+});
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+/// Set when the running thread should give way at the next opportunity.
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+/// Running thread per CPU, readable without the table lock.
+static CURRENT: [AtomicU64; cpu::MAX_CPUS] = [const { AtomicU64::new(0) }; cpu::MAX_CPUS];
+/// Table slot of the running thread per CPU. Changes only once the previous
+/// thread has dropped all its locks, right before the switch.
+static CURRENT_SLOT: [AtomicUsize; cpu::MAX_CPUS] = [const { AtomicUsize::new(0) }; cpu::MAX_CPUS];
+/// Process of the running thread per CPU, 0 for none.
+static CURRENT_PROCESS: [AtomicU64; cpu::MAX_CPUS] = [const { AtomicU64::new(0) }; cpu::MAX_CPUS];
+
+/// Adopts the running boot code as thread 0 and creates the idle thread.
+/// Needs the frame allocator.
+pub fn init() {
+    // main first, so it keeps id 0, which `current_id` reported until now
+    let mut main = Thread::new("main", Priority::Normal, || {}, 0, 0);
+    let idle = new_thread("idle", Priority::Low, idle_main).expect("no stack for the idle thread");
+    let mut table = THREADS.lock();
+    main.state = State::Running;
+    main.detached = true;
+    main.last_run = time::rdtsc();
+    CURRENT[cpu::id()].store(main.id.0, Ordering::Relaxed);
+    table.slots[0] = Some(main);
+    table.current = 0;
+
+    // idle never sits in a run queue; it is what runs when they are empty
+    table.slots[1] = Some(idle);
+    table.idle = 1;
+}
+
+fn idle_main() {
+    loop {
+        // the tick (or whatever interrupt readies a thread) preempts us
+        unsafe { core::arch::asm!("hlt", options(nomem, nostack)) };
+    }
+}
+
+/// Handle for `join`; dropping it detaches the thread.
+pub struct JoinHandle {
+    slot: usize,
+    id: ThreadId,
+}
+
+impl JoinHandle {
+    #[allow(dead_code)]
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let mut table = THREADS.lock();
+        if let Some(t) = table.slots[self.slot].as_mut().filter(|t| t.id == self.id) {
+            if t.state == State::Exited {
+                table.slots[self.slot] = None;
+            } else {
+                t.detached = true;
+            }
+        }
+    }
+}
+
+/// A thread with a fresh stack whose first switch-in lands in
+/// `thread_trampoline`.
+fn new_thread(name: &'static str, priority: Priority, entry: fn()) -> Result<Thread, SpawnError> {
+    let stack = alloc_stack().ok_or(SpawnError::OutOfMemory)?;
+
+    // what switch_context pops: r15, r14, r13, r12, rbx, rbp, return address
+    let top = stack + STACK_SIZE;
+    let frame = (top - 7 * 8) as *mut u64;
+    unsafe {
+        *(stack as *mut u64) = STACK_CANARY;
+        for i in 0..6 {
+            *frame.add(i) = 0;
+        }
+        *frame.add(6) = thread_trampoline as unsafe extern "C" fn() as usize as u64;
+    }
+    Ok(Thread::new(name, priority, entry, frame as u64, stack))
+}
+
+/// Starts `entry` on a new thread at normal priority.
+pub fn spawn(name: &'static str, entry: fn()) -> JoinHandle {
+    spawn_with_priority(name, Priority::Normal, entry)
+}
+
+pub fn spawn_with_priority(name: &'static str, priority: Priority, entry: fn()) -> JoinHandle {
+    match new_thread(name, priority, entry).and_then(insert) {
+        Ok(handle) => handle,
+        Err(e) => panic!("cannot start thread {}: {:?}", name, e),
+    }
+}
+
+/// Starts a thread of process `pid`, running in `space`, that drops
+/// straight into ring 3 with `regs`. Unlike kernel threads, running out of
+/// stacks or slots is the caller's to handle.
+pub fn spawn_user(
+    name: &'static str,
+    pid: Pid,
+    space: AddressSpace,
+    regs: UserRegs,
+) -> Result<JoinHandle, SpawnError> {
+    let mut thread = new_thread(name, Priority::Normal, || {})?;
+    thread.process = Some(pid);
+    thread.space = Some(space);
+    thread.user_start = Some(regs);
+    insert(thread)
+}
+
+fn insert(thread: Thread) -> Result<JoinHandle, SpawnError> {
+    let id = thread.id;
+
+    let mut table = THREADS.lock();
+    let Some(slot) = table.slots.iter().position(|t| t.is_none()) else {
+        drop(table);
+        free_stack(thread.stack);
+        return Err(SpawnError::TableFull);
+    };
+    table.slots[slot] = Some(thread);
+    table.make_ready(slot);
+    Ok(JoinHandle { slot, id })
+}
+
+/// Stacks of exited threads, kept whole for the next thread: runs of
+/// contiguous frames only come from memory that was never allocated, so
+/// handing them back frame by frame would run that out over time.
+struct StackCache {
+    stacks: [u64; MAX_THREADS],
+    len: usize,
+}
+
+static FREE_STACKS: IrqSpinLock<StackCache> = IrqSpinLock::new(StackCache {
+    stacks: [0; MAX_THREADS],
+    len: 0,
+});
+
+fn alloc_stack() -> Option<u64> {
+    let cached = {
+        let mut cache = FREE_STACKS.lock();
+        cache.len.checked_sub(1).map(|last| {
+            cache.len = last;
+            cache.stacks[last]
+        })
+    };
+    cached.or_else(|| frame_alloc::alloc_contiguous(STACK_PAGES))
+}
+
+fn free_stack(stack: u64) {
+    {
+        let mut cache = FREE_STACKS.lock();
+        if cache.len < MAX_THREADS {
+            let len = cache.len;
+            cache.stacks[len] = stack;
+            cache.len += 1;
+            return;
+        }
+    }
+    for i in 0..STACK_PAGES as u64 {
+        frame_alloc::free_frame(stack + i * PAGE_SIZE);
+    }
+}
+
+/// Gives up the CPU, leaving the current thread in `next_state`. A ready
+/// thread goes to the back of its run queue, so it only gets the CPU back
+/// straight away if nothing of the same or higher priority is waiting.
+/// Interrupts are off across the switch and come back as they were when
+/// this thread runs again.
+fn schedule(next_state: State) {
+    // the preemption count is per CPU, not per thread: switching away with it
+    // raised would hand it to whichever thread runs next
+    assert!(cpu::preemptible(), "scheduling in atomic context");
+    let was_enabled = irq::save_and_disable();
+    let mut table = THREADS.lock();
+    NEED_RESCHED.store(false, Ordering::Relaxed);
+
+    let prev = table.current;
+    let idle = table.idle;
+    if next_state == State::Blocked && core::mem::take(&mut table.current().wakeup_pending) {
+        drop(table);
+        irq::restore(was_enabled);
+        return;
+    }
+    if next_state == State::Exited
+        && let Some(joiner) = table.current().joiner.take()
+    {
+        table.wake(joiner);
+    }
+    if next_state == State::Ready && prev != idle {
+        table.make_ready(prev);
+    } else {
+        table.current().state = next_state;
+    }
+    let next = table.pop_ready().unwrap_or(idle);
+
+    let now = time::rdtsc();
+    let t = table.thread(next);
+    t.state = State::Running;
+    t.slice = SLICE_TICKS;
+    if next == prev {
+        NEED_RESCHED.store(false, Ordering::Relaxed);
+        drop(table);
+        irq::restore(was_enabled);
+        return;
+    }
+    t.switches += 1;
+    t.last_run = now;
+    let new_rsp = t.rsp;
+    gdt::set_kernel_stack(t.kernel_stack_top());
+    syscall::set_kernel_stack(t.kernel_stack_top());
+    // kernel threads leave user page tables behind: the process may be torn
+    // down under them
+    t.space.unwrap_or_else(AddressSpace::kernel).activate();
+    CURRENT[cpu::id()].store(t.id.0, Ordering::Relaxed);
+    CURRENT_PROCESS[cpu::id()].store(t.process.map_or(0, Pid::as_u64), Ordering::Relaxed);
+
+    let p = table.thread(prev);
+    if p.stack != 0 && unsafe { *(p.stack as *const u64) } != STACK_CANARY {
+        panic!("thread {} ({}) overflowed its stack", p.id, p.name);
+    }
+    p.runtime += now - p.last_run;
+    let old_rsp = &mut p.rsp as *mut u64;
+    table.current = next;
+    table.context_switches += 1;
+    NEED_RESCHED.store(false, Ordering::Relaxed);
+    drop(table);
+    CURRENT_SLOT[cpu::id()].store(next, Ordering::Relaxed);
+
+    // Safety: slots never move, and `prev` is not touched by anyone else
+    // until it is reaped, which needs us to be off its stack.
+    unsafe { switch_context(old_rsp, new_rsp) };
+
+    finish_switch();
+    irq::restore(was_enabled);
+}
+
+/// Runs on the new thread right after every switch: frees what an exiting
+/// thread could not free itself while still on its stack (or, for the last
+/// thread of a process, in its address space).
+fn finish_switch() {
+    let mut reaped = [None; MAX_THREADS];
+    let mut n = 0;
+    let mut table = THREADS.lock();
+    let current = table.current;
+    for slot in 0..MAX_THREADS {
+        if slot == current {
+            continue;
+        }
+        let Some(t) = table.slots[slot].as_mut() else {
+            continue;
+        };
+        if t.state != State::Exited {
+            continue;
+        }
+        let stack = core::mem::replace(&mut t.stack, 0);
+        let process = t.process;
+        if t.detached {
+            table.slots[slot] = None;
+        }
+        if stack != 0 {
+            free_stack(stack);
+            reaped[n] = process;
+            n += 1;
+        }
+    }
+    drop(table);
+    for pid in reaped[..n].iter().flatten() {
+        process::thread_reaped(*pid);
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+    let (entry, user_start) = {
+        let mut table = THREADS.lock();
+        let t = table.current();
+        (t.entry, t.user_start)
+    };
+    irq::enable();
+    if let Some(regs) = user_start {
+        usermode::enter(&regs);
+    }
+    entry();
+    exit();
+}
+
+/// Timer tick, in interrupt context: charges the running thread.
+pub fn tick() {
+    let mut table = THREADS.lock();
+    if table.current == table.idle {
+        if table.has_ready(Priority::Low) {
+            NEED_RESCHED.store(true, Ordering::Relaxed);
+        }
+        return;
+    }
+    let t = table.current();
+    t.slice = t.slice.saturating_sub(1);
+    let (slice, priority) = (t.slice, t.priority);
+    if slice == 0 && table.has_ready(priority) {
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Called on the way out of every interrupt: switches threads if the tick or
+/// a wakeup asked for it and the interrupted code holds no spinlock.
+pub fn preempt_if_needed() {
+    if NEED_RESCHED.load(Ordering::Relaxed) && cpu::preemptible() {
+        schedule(State::Ready);
+    }
+}
+
+/// Puts the current thread to sleep until `wake`. A `wake` that came in
+/// between deciding to sleep and calling this is not lost: `block` returns
+/// straight away. Wakeups can also be spurious, so callers re-check their
+/// condition.
+pub fn block() {
+    assert!(cpu::preemptible(), "blocking in atomic context");
+    let idle = {
+        let table = THREADS.lock();
+        table.current == table.idle
+    };
+    assert!(!idle, "idle thread cannot block");
+    schedule(State::Blocked);
+}
+
+/// Makes thread `id` runnable again if it is blocked, or cancels its next
+/// `block` if it is not. Safe from interrupt handlers. Returns false if the
+/// thread no longer exists.
+pub fn wake(id: ThreadId) -> bool {
+    THREADS.lock().wake(id)
+}
+
+/// Sleeps for at least `ms` milliseconds. Needs the timer tick running.
+pub fn sleep_ms(ms: u64) {
+    let deadline = time::ticks().saturating_add(timer::ms_to_ticks(ms));
+    let id = current_id();
+    let armed = timer::add(
+        deadline,
+        |id| {
+            wake(ThreadId(id as u64));
+        },
+        id.0 as usize,
+    )
+    .is_some();
+    while time::ticks() < deadline {
+        if armed {
+            block();
+        } else {
+            // every timer is taken: poll rather than fail the sleep
+            yield_now();
+        }
+    }
+}
+
+pub fn yield_now() {
+    schedule(State::Ready);
+}
+
+/// Ends the current thread.
+pub fn exit() -> ! {
+    schedule(State::Exited);
+    unreachable!("exited thread was resumed");
+}
+
+/// Waits for the thread behind `handle` to exit.
+#[allow(dead_code)]
+pub fn join(handle: JoinHandle) {
+    let me = current_id();
+    loop {
+        {
+            let mut table = THREADS.lock();
+            match table.slots[handle.slot].as_mut() {
+                Some(t) if t.id == handle.id && t.state != State::Exited => t.joiner = Some(me),
+                // dropping the handle frees the slot
+                _ => return,
+            }
+        }
+        block();
+    }
+}
+
+/// Id of the running thread; takes no lock.
+pub fn current_id() -> ThreadId {
+    ThreadId(CURRENT[cpu::id()].load(Ordering::Relaxed))
+}
+
+/// Table slot of the running thread, below `MAX_THREADS`; takes no lock.
+/// The boot code is slot 0 before `init` adopts it as thread 0.
+#[cfg_attr(not(feature = "lockdep"), allow(dead_code))]
+pub fn current_slot() -> usize {
+    CURRENT_SLOT[cpu::id()].load(Ordering::Relaxed)
+}
+
+/// Process of the running thread; takes no lock.
+pub fn current_process() -> Option<Pid> {
+    match CURRENT_PROCESS[cpu::id()].load(Ordering::Relaxed) {
+        0 => None,
+        pid => Some(Pid::from_u64(pid)),
+    }
+}
+
+/// Id and name of the running thread, for crash reports: gives up instead of
+/// waiting if the thread table is locked.
+pub fn try_current() -> Option<(ThreadId, &'static str)> {
+    let table = THREADS.try_lock()?;
+    let t = table.slots[table.current].as_ref()?;
+    Some((t.id, t.name))
+}
+
+/// Per-thread statistics, one line each. Gives up if the table is locked,
+/// so it is safe from the panic handler. The table is copied out first, so
+/// slow output does not keep the scheduler locked.
+pub fn dump(mut emit: impl FnMut(fmt::Arguments)) {
+    struct Row {
+        id: ThreadId,
+        name: &'static str,
+        priority: Priority,
+        state: State,
+        runtime: u64,
+        switches: u64,
+    }
+
+    let mut rows = [const { None }; MAX_THREADS];
+    let context_switches = {
+        let Some(table) = THREADS.try_lock() else {
+            emit(format_args!("threads: table busy, not dumped\n"));
+            return;
+        };
+        let now = time::rdtsc();
+        for (slot, t) in table.slots.iter().enumerate() {
+            let Some(t) = t else {
+                continue;
+            };
+            let mut runtime = t.runtime;
+            if slot == table.current {
+                runtime += now - t.last_run;
+            }
+            rows[slot] = Some(Row {
+                id: t.id,
+                name: t.name,
+                priority: t.priority,
+                state: t.state,
+                runtime,
+                switches: t.switches,
+            });
+        }
+        table.context_switches
+    };
+
+    emit(format_args!(
+        "--- threads ({} context switches) ---\n",
+        context_switches
+    ));
+    emit(format_args!(
+        "{:>4} {:<16} {:<6} {:<8} {:>12} {:>10}\n",
+        "id", "name", "prio", "state", "runtime(ms)", "switches"
+    ));
+    for t in rows.iter().flatten() {
+        emit(format_args!(
+            "{:>4} {:<16} {:<6} {:<8} {:>12} {:>10}\n",
+            t.id,
+            t.name,
+            t.priority.name(),
+            t.state.name(),
+            time::tsc_to_ns(t.runtime) / 1_000_000,
+            t.switches
+        ));
+    }
+    emit(format_args!("--- end threads ---\n"));
+}
@@ -0,0 +1,101 @@
+// TSC based clock, calibrated once against PIT channel 2, and the periodic
+// scheduler tick from PIT channel 0.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::port::{inb, outb};
+use crate::sync::seqlock::SeqLock;
+use crate::{irq, thread, timer};
+
+const PIT_HZ: u64 = 1_193_182;
+const PIT_CH0_DATA: u16 = 0x40;
+const PIT_CH2_DATA: u16 = 0x42;
+const PIT_CMD: u16 = 0x43;
+const PIT_GATE: u16 = 0x61; // bit0 = ch2 gate, bit1 = speaker, bit5 = ch2 out
+
+const CALIBRATE_MS: u64 = 10;
+
+/// Scheduler tick rate.
+pub const TICK_HZ: u64 = 1000;
+const TIMER_IRQ: u8 = 0;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Conversion parameters: `ns = tsc * mult >> 32`. Read on every timestamp,
+/// written on calibration.
+#[derive(Clone, Copy)]
+struct Clock {
+    tsc_hz: u64,
+    mult: u64,
+}
+
+static CLOCK: SeqLock<Clock> = SeqLock::new(Clock { tsc_hz: 0, mult: 0 });
+
+#[inline(always)]
+pub fn rdtsc() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        core::arch::asm!("rdtsc", out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags));
+    }
+    ((hi as u64) << 32) | lo as u64
+}
+
+/// Measures the TSC frequency by timing a PIT channel 2 one-shot.
+pub fn init() {
+    let count = (PIT_HZ * CALIBRATE_MS / 1000) as u16;
+    unsafe {
+        // gate low, speaker off
+        let gate = inb(PIT_GATE) & !0x03;
+        outb(PIT_GATE, gate);
+
+        outb(PIT_CMD, 0xB0); // ch2, lobyte/hibyte, mode 0
+        outb(PIT_CH2_DATA, count as u8);
+        outb(PIT_CH2_DATA, (count >> 8) as u8);
+
+        // raising the gate starts the countdown
+        outb(PIT_GATE, gate | 0x01);
+        let start = rdtsc();
+        while inb(PIT_GATE) & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        let end = rdtsc();
+        outb(PIT_GATE, gate);
+
+        let tsc_hz = (end - start) * 1000 / CALIBRATE_MS;
+        let mult = ((1_000_000_000u128 << 32) / tsc_hz.max(1) as u128) as u64;
+        CLOCK.write(Clock { tsc_hz, mult });
+    }
+}
+
+pub fn tsc_hz() -> u64 {
+    CLOCK.read().tsc_hz
+}
+
+/// Converts a TSC reading to nanoseconds; 0 until `init` has run.
+pub fn tsc_to_ns(tsc: u64) -> u64 {
+    let clock = CLOCK.read();
+    ((tsc as u128 * clock.mult as u128) >> 32) as u64
+}
+
+/// Programs PIT channel 0 as a `TICK_HZ` rate generator and hooks IRQ 0.
+pub fn start_tick() {
+    let count = (PIT_HZ / TICK_HZ) as u16;
+    unsafe {
+        outb(PIT_CMD, 0x34); // ch0, lobyte/hibyte, mode 2
+        outb(PIT_CH0_DATA, count as u8);
+        outb(PIT_CH0_DATA, (count >> 8) as u8);
+    }
+    irq::register(TIMER_IRQ, on_tick);
+}
+
+fn on_tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    timer::run(now);
+    thread::tick();
+}
+
+/// Timer ticks since `start_tick`.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
@@ -0,0 +1,205 @@
+// Kernel timers: run a callback once a tick count is reached.
+//
+// Pending timers sit in a hierarchical timer wheel: LEVELS wheels of
+// SLOTS buckets, level n covering deltas up to SLOTS^(n+1) ticks. Adding and
+// cancelling are O(1); each tick expires one level 0 bucket, and whenever a
+// lower level wraps the matching bucket of the level above is cascaded down.
+// Timers further out than the wheel reaches park in the last bucket of the
+// top level and are re-filed when it comes round.
+//
+// Callbacks run from the timer interrupt with the wheel unlocked, so they may
+// add or cancel timers but must not block.
+
+use crate::sync::irq_spinlock::IrqSpinLock;
+use crate::time;
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 4;
+const MAX_TIMERS: usize = 128;
+const NIL: u16 = u16::MAX;
+
+pub type Callback = fn(usize);
+
+/// Handle for `cancel`; stale handles (fired or cancelled timers) are
+/// recognized by their generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: u16,
+    generation: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    deadline: u64,
+    callback: Callback,
+    data: usize,
+    generation: u32,
+    /// Bucket the timer is linked into, NIL when free.
+    bucket: u16,
+    prev: u16,
+    next: u16,
+}
+
+impl Timer {
+    const FREE: Self = Self {
+        deadline: 0,
+        callback: |_| {},
+        data: 0,
+        generation: 0,
+        bucket: NIL,
+        prev: NIL,
+        next: NIL,
+    };
+}
+
+struct Wheel {
+    /// Last tick processed.
+    now: u64,
+    timers: [Timer; MAX_TIMERS],
+    /// First timer of each bucket, level-major.
+    heads: [u16; LEVELS * SLOTS],
+}
+
+impl Wheel {
+    /// `earliest` is the first tick the timer may still fire on: the next
+    /// one for new timers, the current one while cascading.
+    fn bucket_for(&self, deadline: u64, earliest: u64) -> usize {
+        let deadline = deadline.max(earliest);
+        let delta = deadline - self.now;
+        for level in 0..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            if delta < (SLOTS as u64) << shift {
+                return level * SLOTS + (deadline >> shift) as usize % SLOTS;
+            }
+        }
+        // beyond the wheel: the top level bucket that comes round last
+        let shift = SLOT_BITS * (LEVELS - 1) as u32;
+        let last = (self.now >> shift) + SLOTS as u64 - 1;
+        (LEVELS - 1) * SLOTS + last as usize % SLOTS
+    }
+
+    fn link(&mut self, index: u16, earliest: u64) {
+        let bucket = self.bucket_for(self.timers[index as usize].deadline, earliest);
+        let head = self.heads[bucket];
+        let t = &mut self.timers[index as usize];
+        t.bucket = bucket as u16;
+        t.prev = NIL;
+        t.next = head;
+        if head != NIL {
+            self.timers[head as usize].prev = index;
+        }
+        self.heads[bucket] = index;
+    }
+
+    fn unlink(&mut self, index: u16) {
+        let Timer {
+            bucket, prev, next, ..
+        } = self.timers[index as usize];
+        if prev == NIL {
+            self.heads[bucket as usize] = next;
+        } else {
+            self.timers[prev as usize].next = next;
+        }
+        if next != NIL {
+            self.timers[next as usize].prev = prev;
+        }
+        self.timers[index as usize].bucket = NIL;
+    }
+
+    /// Re-files every timer in `bucket` relative to the current tick.
+    fn cascade(&mut self, bucket: usize) {
+        let mut index = core::mem::replace(&mut self.heads[bucket], NIL);
+        while index != NIL {
+            let next = self.timers[index as usize].next;
+            self.link(index, self.now);
+            index = next;
+        }
+    }
+
+    /// Moves the wheel to `now + 1`.
+    fn advance(&mut self) {
+        self.now += 1;
+        for level in 1..LEVELS {
+            let shift = SLOT_BITS * level as u32;
+            if self.now & ((1 << shift) - 1) != 0 {
+                break;
+            }
+            self.cascade(level * SLOTS + (self.now >> shift) as usize % SLOTS);
+        }
+    }
+
+    /// Unlinks one timer that is due, if any is left in the current bucket.
+    fn pop_expired(&mut self) -> Option<(Callback, usize)> {
+        let index = self.heads[self.now as usize % SLOTS];
+        if index == NIL {
+            return None;
+        }
+        self.unlink(index);
+        let t = &mut self.timers[index as usize];
+        t.generation = t.generation.wrapping_add(1);
+        Some((t.callback, t.data))
+    }
+}
+
+static WHEEL: IrqSpinLock<Wheel> = IrqSpinLock::new(Wheel {
+    now: 0,
+    timers: [Timer::FREE; MAX_TIMERS],
+    heads: [NIL; LEVELS * SLOTS],
+});
+
+/// Calls `callback(data)` from the timer interrupt once `time::ticks()`
+/// reaches `deadline` (on the next tick if it already has). Returns `None`
+/// if all timers are in use.
+pub fn add(deadline: u64, callback: Callback, data: usize) -> Option<TimerId> {
+    let mut wheel = WHEEL.lock();
+    let index = wheel.timers.iter().position(|t| t.bucket == NIL)? as u16;
+    let t = &mut wheel.timers[index as usize];
+    t.deadline = deadline;
+    t.callback = callback;
+    t.data = data;
+    let generation = t.generation;
+    let earliest = wheel.now + 1;
+    wheel.link(index, earliest);
+    Some(TimerId { index, generation })
+}
+
+/// `add` with a deadline `ms` milliseconds from now.
+pub fn add_ms(ms: u64, callback: Callback, data: usize) -> Option<TimerId> {
+    let deadline = time::ticks().saturating_add(ms_to_ticks(ms));
+    add(deadline, callback, data)
+}
+
+/// Stops a pending timer. Returns false if it already fired or was
+/// cancelled.
+#[allow(dead_code)]
+pub fn cancel(id: TimerId) -> bool {
+    let mut wheel = WHEEL.lock();
+    let t = &wheel.timers[id.index as usize];
+    if t.bucket == NIL || t.generation != id.generation {
+        return false;
+    }
+    wheel.unlink(id.index);
+    let t = &mut wheel.timers[id.index as usize];
+    t.generation = t.generation.wrapping_add(1);
+    true
+}
+
+/// Rounds up, so a timeout never fires early. Saturates rather than wraps:
+/// a timeout too long to count is as good as forever.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    ms.saturating_mul(time::TICK_HZ).div_ceil(1000)
+}
+
+/// Runs everything due up to tick `now`. Called from the timer interrupt.
+pub fn run(now: u64) {
+    let mut wheel = WHEEL.lock();
+    while wheel.now < now {
+        wheel.advance();
+        while let Some((callback, data)) = wheel.pop_expired() {
+            drop(wheel);
+            callback(data);
+            wheel = WHEEL.lock();
+        }
+    }
+}
@@ -0,0 +1,106 @@
+// Entering ring 3.
+//
+// A kernel thread becomes a user thread by building an interrupt frame that
+// points at user code and `iretq`ing through it. From then on interrupts and
+// exceptions bring the CPU back onto the thread's kernel stack via TSS.rsp0,
+// which the scheduler keeps up to date.
+
+use crate::gdt;
+use crate::paging::{USER_BASE, USER_END};
+
+/// IF set, reserved bit 1 set, IOPL 0.
+const USER_RFLAGS: u64 = 0x202;
+/// Flags user code may set: CF, PF, AF, ZF, SF, TF, DF, OF and AC.
+const RFLAGS_USER_MASK: u64 = 0x0004_0DD5;
+
+/// The registers a user thread starts with: general purpose registers, then
+/// an interrupt frame, in the order `enter` pops them. `enter` fills in the
+/// segments and keeps only harmless flags.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UserRegs {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl UserRegs {
+    /// Fresh start at `entry` with stack pointer `stack`; no kernel values
+    /// leak into the other registers.
+    pub fn new(entry: u64, stack: u64) -> Self {
+        Self {
+            rip: entry,
+            rsp: stack,
+            rflags: USER_RFLAGS,
+            ..Self::default()
+        }
+    }
+
+    /// Whether rip and rsp point into user space; `enter` needs both.
+    pub fn in_user_space(&self) -> bool {
+        (USER_BASE..USER_END).contains(&self.rip) && (USER_BASE..=USER_END).contains(&self.rsp)
+    }
+}
+
+/// Drops the current thread into ring 3 with `regs`. rip and rsp must lie in
+/// mapped user memory. Never returns: the thread only comes back into the
+/// kernel through interrupts and system calls.
+pub fn enter(regs: &UserRegs) -> ! {
+    assert!(
+        regs.in_user_space(),
+        "user entry {:#x} or stack {:#x} outside user space",
+        regs.rip,
+        regs.rsp
+    );
+    let regs = UserRegs {
+        cs: gdt::USER_CS as u64,
+        ss: gdt::USER_DS as u64,
+        rflags: (regs.rflags & RFLAGS_USER_MASK) | USER_RFLAGS,
+        ..*regs
+    };
+    unsafe {
+        core::arch::asm!(
+            "cli",
+            "mov ds, {ds:x}",
+            "mov es, {ds:x}",
+            // the copy is on this stack, which we are leaving for good
+            "mov rsp, {regs}",
+            "pop rax",
+            "pop rbx",
+            "pop rcx",
+            "pop rdx",
+            "pop rsi",
+            "pop rdi",
+            "pop rbp",
+            "pop r8",
+            "pop r9",
+            "pop r10",
+            "pop r11",
+            "pop r12",
+            "pop r13",
+            "pop r14",
+            "pop r15",
+            "iretq",
+            ds = in(reg) gdt::USER_DS as u64,
+            regs = in(reg) &regs,
+            options(noreturn)
+        );
+    }
+}
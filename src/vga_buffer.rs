@@ -1,20 +1,40 @@
 use core::fmt;
 use core::ptr::{read_volatile, write_volatile};
 
+use crate::ansi::{AnsiTarget, Attr, DEFAULT_ATTR, DEFAULT_BG, DEFAULT_FG, Parser, TermColor};
+use crate::console::{self, Console};
+use crate::port::{inb, outb};
+use crate::sync::irq_spinlock::IrqSpinLock;
 use crate::sync::lazy::Lazy;
-use crate::sync::spinlock::SpinLock;
 
-const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
+const TAB_WIDTH: usize = 8;
+const SCROLLBACK_LINES: usize = 256;
 
-pub static WRITER: Lazy<SpinLock<Writer>> = Lazy::new(|| {
-    SpinLock::new(Writer {
+// CRTC registers (color mode)
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+const CRTC_CURSOR_START: u8 = 0x0A;
+const CRTC_CURSOR_END: u8 = 0x0B;
+const CRTC_CURSOR_HIGH: u8 = 0x0E;
+const CRTC_CURSOR_LOW: u8 = 0x0F;
+const CURSOR_DISABLE: u8 = 0x20;
+
+pub static WRITER: Lazy<IrqSpinLock<Writer>> = Lazy::new(|| {
+    IrqSpinLock::new(Writer {
         column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
+        row_position: 0,
+        color_code: ColorCode::new(DEFAULT_FG, DEFAULT_BG),
+        ansi: Parser::new(DEFAULT_ATTR),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        scrollback: unsafe { &mut *core::ptr::addr_of_mut!(SCROLLBACK) },
     })
 });
 
+// Lives outside WRITER so the Lazy init doesn't build ~45KiB on the stack.
+static mut SCROLLBACK: Scrollback = Scrollback::new();
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -37,12 +57,85 @@ pub enum Color {
     White = 15,
 }
 
+impl Color {
+    const ALL: [Color; 16] = [
+        Color::Black,
+        Color::Blue,
+        Color::Green,
+        Color::Cyan,
+        Color::Red,
+        Color::Magenta,
+        Color::Brown,
+        Color::LightGray,
+        Color::DarkGray,
+        Color::LightBlue,
+        Color::LightGreen,
+        Color::LightCyan,
+        Color::LightRed,
+        Color::Pink,
+        Color::Yellow,
+        Color::White,
+    ];
+
+    // Standard VGA palette, indexed by discriminant.
+    const PALETTE: [(u8, u8, u8); 16] = [
+        (0x00, 0x00, 0x00),
+        (0x00, 0x00, 0xAA),
+        (0x00, 0xAA, 0x00),
+        (0x00, 0xAA, 0xAA),
+        (0xAA, 0x00, 0x00),
+        (0xAA, 0x00, 0xAA),
+        (0xAA, 0x55, 0x00),
+        (0xAA, 0xAA, 0xAA),
+        (0x55, 0x55, 0x55),
+        (0x55, 0x55, 0xFF),
+        (0x55, 0xFF, 0x55),
+        (0x55, 0xFF, 0xFF),
+        (0xFF, 0x55, 0x55),
+        (0xFF, 0x55, 0xFF),
+        (0xFF, 0xFF, 0x55),
+        (0xFF, 0xFF, 0xFF),
+    ];
+
+    /// ANSI color number (0-7 normal, 8-15 bright) to the VGA color.
+    /// ANSI orders red/blue the other way round.
+    pub fn from_ansi(n: u8) -> Self {
+        const MAP: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+        let n = n & 0xF;
+        Self::ALL[(MAP[(n & 7) as usize] | (n & 8)) as usize]
+    }
+
+    /// The high-intensity variant (what SGR bold does on a VGA console).
+    pub fn bright(self) -> Self {
+        Self::ALL[self as usize | 8]
+    }
+
+    pub fn rgb(self) -> (u8, u8, u8) {
+        Self::PALETTE[self as usize]
+    }
+
+    pub fn nearest(r: u8, g: u8, b: u8) -> Self {
+        let dist = |&(pr, pg, pb): &(u8, u8, u8)| {
+            let dr = pr as i32 - r as i32;
+            let dg = pg as i32 - g as i32;
+            let db = pb as i32 - b as i32;
+            dr * dr + dg * dg + db * db
+        };
+        let (i, _) = Self::PALETTE
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, p)| dist(p))
+            .unwrap();
+        Self::ALL[i]
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> Self {
+    pub const fn new(foreground: Color, background: Color) -> Self {
         Self(((background as u8) << 4) | (foreground as u8))
     }
 }
@@ -54,32 +147,91 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
+impl ScreenChar {
+    const BLANK: Self = Self {
+        char: b' ',
+        color_code: ColorCode::new(DEFAULT_FG, DEFAULT_BG),
+    };
+}
+
+type Line = [ScreenChar; BUFFER_WIDTH];
+
 #[repr(transparent)]
 struct Buffer {
-    chars: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    chars: [Line; BUFFER_HEIGHT],
+}
+
+/// Lines scrolled off the top of the screen, oldest first once full.
+struct Scrollback {
+    lines: [Line; SCROLLBACK_LINES],
+    head: usize,
+    len: usize,
+    /// How many lines up from the live screen we are showing; 0 = live.
+    view_offset: usize,
+    /// Copy of the live screen while a history view is displayed.
+    live: [Line; BUFFER_HEIGHT],
+}
+
+impl Scrollback {
+    const fn new() -> Self {
+        Self {
+            lines: [[ScreenChar::BLANK; BUFFER_WIDTH]; SCROLLBACK_LINES],
+            head: 0,
+            len: 0,
+            view_offset: 0,
+            live: [[ScreenChar::BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        }
+    }
+
+    fn push(&mut self, line: Line) {
+        self.lines[self.head] = line;
+        self.head = (self.head + 1) % SCROLLBACK_LINES;
+        self.len = (self.len + 1).min(SCROLLBACK_LINES);
+    }
+
+    /// `i`-th oldest line still kept.
+    fn line(&self, i: usize) -> &Line {
+        let oldest = (self.head + SCROLLBACK_LINES - self.len) % SCROLLBACK_LINES;
+        &self.lines[(oldest + i) % SCROLLBACK_LINES]
+    }
 }
 
 pub struct Writer {
     column_position: usize,
+    row_position: usize,
     color_code: ColorCode,
+    ansi: Parser,
     buffer: &'static mut Buffer,
+    scrollback: &'static mut Scrollback,
 }
 
 impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            b'\r' => self.column_position = 0,
+            b'\t' => {
+                let next = (self.column_position / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column_position < next.min(BUFFER_WIDTH) {
+                    self.write_byte(b' ');
+                }
+            }
+            0x08 => {
+                // backspace: move left, the next byte overwrites
+                self.column_position = self.column_position.min(BUFFER_WIDTH - 1);
+                self.column_position = self.column_position.saturating_sub(1);
+            }
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
                 }
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
                 let cc = self.color_code;
 
                 unsafe {
                     write_volatile(
-                        &mut (*self.buffer).chars[row][col],
+                        &mut self.buffer.chars[row][col],
                         ScreenChar {
                             char: byte,
                             color_code: cc,
@@ -93,34 +245,170 @@ impl Writer {
     }
 
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
+        // new output always snaps back to the live screen
+        if self.scrollback.view_offset != 0 {
+            self.scroll_view(-(self.scrollback.view_offset as isize));
+        }
+
+        self.with_ansi(|ansi, w| {
+            for c in s.chars() {
+                ansi.feed(c, w);
             }
+        });
+
+        self.update_cursor();
+    }
+
+    /// Writes `s` in the given colors, then restores the current attributes.
+    pub fn write_colored(&mut self, s: &str, fg: Color, bg: Color) {
+        let mut saved = DEFAULT_ATTR;
+        self.with_ansi(|ansi, w| {
+            saved = ansi.attr();
+            ansi.set_attr(Attr::new(fg, bg), w);
+        });
+        self.write_string(s);
+        self.with_ansi(|ansi, w| ansi.set_attr(saved, w));
+    }
+
+    // The parser drives `self` as its target, so borrow it out for the call.
+    fn with_ansi(&mut self, f: impl FnOnce(&mut Parser, &mut Self)) {
+        let mut ansi = core::mem::replace(&mut self.ansi, Parser::new(DEFAULT_ATTR));
+        f(&mut ansi, self);
+        self.ansi = ansi;
+    }
+
+    /// Moves the cursor to `(row, col)`, clamped to the screen.
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.row_position = row.min(BUFFER_HEIGHT - 1);
+        self.column_position = col.min(BUFFER_WIDTH - 1);
+        self.update_cursor();
+    }
+
+    pub fn clear_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.set_position(0, 0);
+    }
+
+    /// Scrolls the view `lines` towards older output (negative = newer).
+    pub fn scroll_view(&mut self, lines: isize) {
+        let cur = self.scrollback.view_offset;
+        let target = (cur as isize + lines).clamp(0, self.scrollback.len as isize) as usize;
+        if target == cur {
+            return;
+        }
+
+        if cur == 0 {
+            for row in 0..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    self.scrollback.live[row][col] =
+                        unsafe { read_volatile(&self.buffer.chars[row][col]) };
+                }
+            }
+            disable_cursor();
+        }
+
+        self.scrollback.view_offset = target;
+
+        // view = last BUFFER_HEIGHT lines of [history..., live] ending `target` early
+        let len = self.scrollback.len;
+        for row in 0..BUFFER_HEIGHT {
+            let i = len + row - target;
+            let line = if i < len {
+                *self.scrollback.line(i)
+            } else {
+                self.scrollback.live[i - len]
+            };
+            for (col, ch) in line.iter().enumerate() {
+                unsafe { write_volatile(&mut self.buffer.chars[row][col], *ch) };
+            }
+        }
+
+        if target == 0 {
+            enable_cursor();
+            self.update_cursor();
+        }
+    }
+
+    fn update_cursor(&self) {
+        if self.scrollback.view_offset != 0 {
+            return;
+        }
+        let col = self.column_position.min(BUFFER_WIDTH - 1);
+        let pos = (self.row_position * BUFFER_WIDTH + col) as u16;
+        unsafe {
+            outb(CRTC_INDEX, CRTC_CURSOR_HIGH);
+            outb(CRTC_DATA, (pos >> 8) as u8);
+            outb(CRTC_INDEX, CRTC_CURSOR_LOW);
+            outb(CRTC_DATA, pos as u8);
         }
     }
 
     fn new_line(&mut self) {
+        self.column_position = 0;
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+            return;
+        }
+
+        let mut top = [ScreenChar::BLANK; BUFFER_WIDTH];
+        for (col, ch) in top.iter_mut().enumerate() {
+            *ch = unsafe { read_volatile(&self.buffer.chars[0][col]) };
+        }
+        self.scrollback.push(top);
+
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
-                let ch = unsafe { read_volatile(&(*self.buffer).chars[row][col]) };
-                unsafe { write_volatile(&mut (*self.buffer).chars[row - 1][col], ch) };
+                let ch = unsafe { read_volatile(&self.buffer.chars[row][col]) };
+                unsafe { write_volatile(&mut self.buffer.chars[row - 1][col], ch) };
             }
         }
         self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
     }
 
     fn clear_row(&mut self, row: usize) {
+        self.erase(row, 0, BUFFER_WIDTH);
+    }
+}
+
+impl AnsiTarget for Writer {
+    fn size(&self) -> (usize, usize) {
+        (BUFFER_HEIGHT, BUFFER_WIDTH)
+    }
+
+    fn cursor(&self) -> (usize, usize) {
+        (
+            self.row_position,
+            self.column_position.min(BUFFER_WIDTH - 1),
+        )
+    }
+
+    fn set_cursor(&mut self, row: usize, col: usize) {
+        self.set_position(row, col);
+    }
+
+    fn put(&mut self, c: char) {
+        match c {
+            '\x20'..='\x7e' | '\n' | '\r' | '\t' | '\x08' => self.write_byte(c as u8),
+            _ => self.write_byte(0xfe),
+        }
+    }
+
+    fn erase(&mut self, row: usize, from: usize, to: usize) {
         let blank = ScreenChar {
             char: b' ',
             color_code: self.color_code,
         };
-        for col in 0..BUFFER_WIDTH {
-            unsafe { write_volatile(&mut (*self.buffer).chars[row][col], blank) };
+        for col in from..to.min(BUFFER_WIDTH) {
+            unsafe { write_volatile(&mut self.buffer.chars[row][col], blank) };
         }
     }
+
+    fn set_colors(&mut self, fg: TermColor, bg: TermColor) {
+        // text mode has the 16 colors only
+        self.color_code = ColorCode::new(fg.nearest(), bg.nearest());
+    }
 }
 
 impl fmt::Write for Writer {
@@ -130,8 +418,48 @@ impl fmt::Write for Writer {
     }
 }
 
+fn enable_cursor() {
+    unsafe {
+        // underline cursor on scanlines 14-15
+        outb(CRTC_INDEX, CRTC_CURSOR_START);
+        outb(CRTC_DATA, (inb(CRTC_DATA) & 0xC0) | 14);
+        outb(CRTC_INDEX, CRTC_CURSOR_END);
+        outb(CRTC_DATA, (inb(CRTC_DATA) & 0xE0) | 15);
+    }
+}
+
+fn disable_cursor() {
+    unsafe {
+        outb(CRTC_INDEX, CRTC_CURSOR_START);
+        outb(CRTC_DATA, CURSOR_DISABLE);
+    }
+}
+
+pub struct VgaConsole;
+
+pub static CONSOLE: VgaConsole = VgaConsole;
+
+impl Console for VgaConsole {
+    fn name(&self) -> &'static str {
+        "vga"
+    }
+
+    fn write_str(&self, s: &str) {
+        print(s);
+    }
+}
+
+pub fn init() {
+    enable_cursor();
+    WRITER.get().lock().clear_screen();
+    console::register(&CONSOLE);
+}
+
 pub fn print(s: &str) {
-    let lock = WRITER.get();
-    let mut w = lock.lock();
-    w.write_string(s);
+    WRITER.get().lock().write_string(s);
+}
+
+#[allow(dead_code)]
+pub fn print_colored(s: &str, fg: Color, bg: Color) {
+    WRITER.get().lock().write_colored(s, fg, bg);
 }
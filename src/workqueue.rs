@@ -0,0 +1,100 @@
+// Kernel workqueue: a small pool of worker threads running submitted work
+// in FIFO order. Work runs in thread context, so unlike tasklets it may
+// sleep, take mutexes and allocate freely.
+//
+// Like tasklets, work items are statics owned by their submitter and linked
+// through themselves, so submitting never allocates. That rules out boxed
+// closures: the kernel heap never frees, so every submission would leak its
+// box. A closure that captures nothing still works, as it is a `fn(usize)`;
+// anything it would capture goes in `data` or in statics beside the item.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::sync::irq_spinlock::IrqSpinLock;
+use crate::sync::waitqueue::WaitQueue;
+use crate::thread;
+
+const WORKER_NAMES: [&str; 2] = ["kworker/0", "kworker/1"];
+
+pub struct Work {
+    func: fn(usize),
+    data: usize,
+    queued: AtomicBool,
+    next: AtomicPtr<Work>,
+}
+
+/// Queued work, oldest at `head`; links only change under the lock.
+struct Queue {
+    head: *mut Work,
+    tail: *mut Work,
+}
+
+// Safety: the pointers are to `'static` work items, which are `Sync`.
+unsafe impl Send for Queue {}
+
+static QUEUE: IrqSpinLock<Queue> = IrqSpinLock::new(Queue {
+    head: ptr::null_mut(),
+    tail: ptr::null_mut(),
+});
+static WORK_READY: WaitQueue = WaitQueue::new();
+
+impl Work {
+    pub const fn new(func: fn(usize), data: usize) -> Self {
+        Self {
+            func,
+            data,
+            queued: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Queues the work to run on a worker thread unless it is already
+    /// queued. Safe from interrupt handlers and tasklets. Work submitted
+    /// again while it runs may run on a second worker at the same time.
+    pub fn submit(&'static self) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let this = self as *const Work as *mut Work;
+        self.next.store(ptr::null_mut(), Ordering::Relaxed);
+        {
+            let mut q = QUEUE.lock();
+            match unsafe { q.tail.as_ref() } {
+                Some(tail) => tail.next.store(this, Ordering::Relaxed),
+                None => q.head = this,
+            }
+            q.tail = this;
+        }
+        WORK_READY.wake_one();
+    }
+}
+
+/// Starts the worker threads. Needs the scheduler.
+pub fn init() {
+    for name in WORKER_NAMES {
+        // detached: workers never exit
+        thread::spawn(name, worker);
+    }
+}
+
+fn pop() -> Option<&'static Work> {
+    let mut q = QUEUE.lock();
+    let work = unsafe { q.head.as_ref() }?;
+    q.head = work.next.load(Ordering::Relaxed);
+    if q.head.is_null() {
+        q.tail = ptr::null_mut();
+    }
+    Some(work)
+}
+
+fn worker() {
+    loop {
+        WORK_READY.wait_until(|| !QUEUE.lock().head.is_null());
+        if let Some(work) = pop() {
+            // cleared first: the work may submit itself again
+            work.queued.store(false, Ordering::Release);
+            (work.func)(work.data);
+        }
+    }
+}